@@ -2,6 +2,7 @@ use super::CLICommand;
 use crate::subcommands::run_add_account;
 use crate::{tag_parser, tags_validator, Tag};
 use clap::{App, Arg, ArgMatches, SubCommand};
+use std::path::Path;
 
 pub struct AddAccountCommand {}
 
@@ -38,6 +39,16 @@ impl CLICommand for AddAccountCommand {
                     .multiple(true) // NOTE: Don't put positional args after this
                     .validator(tags_validator),
             )
+            .arg(
+                Arg::with_name("template")
+                    .long("template")
+                    .help("Path to a customised role CloudFormation template")
+                    .long_help(
+                        "Path to a customised role CloudFormation template. The built in \
+template can be printed with: builder template print role",
+                    )
+                    .takes_value(true),
+            )
     }
 
     fn command_name(&self) -> &'static str {
@@ -52,7 +63,9 @@ impl CLICommand for AddAccountCommand {
             .map(|values| values.map(parse_tags).flatten().collect())
             .unwrap_or(vec![]);
 
-        let result = run_add_account(profile, tags).await;
+        let template_path = matches.value_of("template").map(Path::new);
+
+        let result = run_add_account(profile, tags, template_path).await;
         match result {
             Ok(_) => {
                 println!("Successfully added account");
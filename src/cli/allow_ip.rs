@@ -0,0 +1,55 @@
+use super::CLICommand;
+use crate::subcommands::run_allow_ip;
+use crate::{cidr_parser, cidr_validator};
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+pub struct AllowIpCommand {}
+
+impl AllowIpCommand {
+    pub fn new() -> Self {
+        AllowIpCommand {}
+    }
+}
+
+#[async_trait::async_trait]
+impl CLICommand for AllowIpCommand {
+    fn subcommand(&self) -> App<'_, '_> {
+        SubCommand::with_name("allow-ip")
+            .about("Change which IP addresses can SSH to the instance, e.g. when your IP changes")
+            .arg(
+                Arg::with_name("cidr")
+                    .long("cidr")
+                    .help("IPv4 range to allow. Can be repeated. Defaults to the public IP of this machine")
+                    .takes_value(true)
+                    .number_of_values(1)
+                    .multiple(true)
+                    .validator(cidr_validator),
+            )
+            .arg(
+                Arg::with_name("add")
+                    .long("add")
+                    .help("Keep the currently allowed ranges instead of replacing them"),
+            )
+    }
+
+    fn command_name(&self) -> &'static str {
+        "allow-ip"
+    }
+
+    async fn run_fn(&self, matches: &ArgMatches<'_>) {
+        let cidrs = matches
+            .values_of("cidr")
+            .map(|cidrs| cidrs.map(|cidr| cidr_parser(cidr).unwrap()).collect())
+            .unwrap_or_default();
+
+        let result = run_allow_ip(cidrs, matches.is_present("add")).await;
+        match result {
+            Ok(allowed_cidrs) => {
+                println!("SSH is allowed from: {}", allowed_cidrs.join(", "));
+            }
+            Err(err) => {
+                println!("Failed to update allowed IPs with error: {:#?}", err);
+            }
+        }
+    }
+}
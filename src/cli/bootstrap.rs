@@ -1,6 +1,12 @@
 use super::CLICommand;
-use crate::{run_bootstrap, tag_parser, tags_validator, Tag};
+use crate::subcommands::InstanceSettings;
+use crate::{
+    architecture_parser, architecture_validator, cidr_parser, cidr_validator, region_parser,
+    region_validator, run_bootstrap, tag_parser, tags_validator, transport_parser,
+    transport_validator, Tag,
+};
 use clap::{App, Arg, ArgMatches, SubCommand};
+use std::path::Path;
 
 pub struct BootstrapCommand {}
 
@@ -24,6 +30,15 @@ impl CLICommand for BootstrapCommand {
                     .hide_env_values(true)
                     .required(true),
             )
+            .arg(
+                Arg::with_name("region")
+                    .long("region")
+                    .help("AWS region in which to create the builder instance")
+                    .env("AWS_REGION")
+                    .takes_value(true)
+                    .default_value("us-east-1")
+                    .validator(region_validator),
+            )
             .arg(
                 Arg::with_name("tags")
                     .long("tags")
@@ -37,6 +52,74 @@ impl CLICommand for BootstrapCommand {
                     .multiple(true) // NOTE: Don't put positional args after this
                     .validator(tags_validator),
             )
+            .arg(
+                Arg::with_name("instance_type")
+                    .long("instance-type")
+                    .help("EC2 instance type of the builder, e.g. t3.large or t4g.large")
+                    .takes_value(true)
+                    .default_value("t2.micro"),
+            )
+            .arg(
+                Arg::with_name("volume_size")
+                    .long("volume-size")
+                    .help(
+                        "Size of the root volume in GiB. Docker images and layers are stored here",
+                    )
+                    .takes_value(true)
+                    .default_value("30")
+                    .validator(volume_size_validator),
+            )
+            .arg(
+                Arg::with_name("volume_type")
+                    .long("volume-type")
+                    .help("EBS volume type of the root volume")
+                    .takes_value(true)
+                    .possible_values(&["gp2", "gp3", "standard"])
+                    .default_value("gp3"),
+            )
+            .arg(
+                Arg::with_name("architecture")
+                    .long("architecture")
+                    .help("CPU architecture of the builder. Use arm64 with Graviton instance types")
+                    .takes_value(true)
+                    .default_value("x86_64")
+                    .validator(architecture_validator),
+            )
+            .arg(
+                Arg::with_name("allow_cidr")
+                    .long("allow-cidr")
+                    .help("IPv4 range allowed to SSH to the instance. Can be repeated")
+                    .long_help(
+                        "IPv4 range allowed to SSH to the instance. Can be repeated. Defaults to \
+the public IP of this machine. Use the allow-ip command to change it later",
+                    )
+                    .takes_value(true)
+                    .number_of_values(1)
+                    .multiple(true)
+                    .validator(cidr_validator),
+            )
+            .arg(
+                Arg::with_name("transport")
+                    .long("transport")
+                    .help("How commands reach the instance: ssh or ssm")
+                    .long_help(
+                        "How commands reach the instance. ssm runs commands through SSM Session \
+Manager & stages files in S3, for accounts which don't allow inbound SSH",
+                    )
+                    .takes_value(true)
+                    .default_value("ssh")
+                    .validator(transport_validator),
+            )
+            .arg(
+                Arg::with_name("template")
+                    .long("template")
+                    .help("Path to a customised instance CloudFormation template")
+                    .long_help(
+                        "Path to a customised instance CloudFormation template. The built in \
+template can be printed with: builder template print instance",
+                    )
+                    .takes_value(true),
+            )
     }
 
     fn command_name(&self) -> &'static str {
@@ -45,17 +128,52 @@ impl CLICommand for BootstrapCommand {
 
     async fn run_fn(&self, matches: &ArgMatches<'_>) {
         let profile = matches.value_of("profile").unwrap();
+        // Values read from AWS_PROFILE aren't counted as occurrences
+        let explicit_profile = matches.occurrences_of("profile") > 0;
+        let region = region_parser(matches.value_of("region").unwrap()).unwrap();
 
         let tags = matches
             .values_of("tags")
-            .map(|values| values.map(parse_tags).flatten().collect())
-            .unwrap_or(vec![]);
+            .map(|values| values.flat_map(parse_tags).collect())
+            .unwrap_or_default();
+
+        let template_path = matches.value_of("template").map(Path::new);
 
-        let result = run_bootstrap(profile.to_owned(), tags).await;
+        let settings = InstanceSettings {
+            instance_type: matches.value_of("instance_type").unwrap().to_owned(),
+            volume_size: matches.value_of("volume_size").unwrap().parse().unwrap(),
+            volume_type: matches.value_of("volume_type").unwrap().to_owned(),
+            architecture: architecture_parser(matches.value_of("architecture").unwrap()).unwrap(),
+            allowed_cidrs: matches
+                .values_of("allow_cidr")
+                .map(|cidrs| cidrs.map(|cidr| cidr_parser(cidr).unwrap()).collect())
+                .unwrap_or_default(),
+            transport: transport_parser(matches.value_of("transport").unwrap()).unwrap(),
+        };
+
+        let result = run_bootstrap(
+            profile.to_owned(),
+            explicit_profile,
+            region,
+            tags,
+            template_path,
+            settings,
+        )
+        .await;
         println!("Finished bootstrap with result: {:?}", result);
     }
 }
 
 fn parse_tags(data: &str) -> Vec<Tag> {
-    tag_parser(data.to_owned()).unwrap_or(vec![])
+    tag_parser(data.to_owned()).unwrap_or_default()
+}
+
+pub fn volume_size_validator(size: String) -> Result<(), String> {
+    match size.parse::<u32>() {
+        Ok(size) if size >= 8 => Ok(()),
+        _ => Err(format!(
+            "Volume size ({}) must be a whole number of GiB, at least 8",
+            size
+        )),
+    }
 }
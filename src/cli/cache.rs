@@ -0,0 +1,130 @@
+use super::CLICommand;
+use crate::subcommands::{cache_usage, prune_cache, set_auto_prune, PrunePolicy};
+use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches, SubCommand};
+
+pub struct CacheCommand {}
+
+impl CacheCommand {
+    pub fn new() -> Self {
+        CacheCommand {}
+    }
+}
+
+#[async_trait::async_trait]
+impl CLICommand for CacheCommand {
+    fn subcommand(&self) -> App<'_, '_> {
+        SubCommand::with_name("cache")
+            .about("Manage the docker layer cache kept on the instance between builds")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                SubCommand::with_name("usage")
+                    .about("Show the space used by images & build cache and the free space left"),
+            )
+            .subcommand(
+                SubCommand::with_name("prune")
+                    .about("Remove part of the cache")
+                    .arg(
+                        Arg::with_name("older-than")
+                            .long("older-than")
+                            .help("Remove build cache not used for this many hours & dangling images")
+                            .takes_value(true)
+                            .validator(whole_number_validator),
+                    )
+                    .arg(
+                        Arg::with_name("keep-gb")
+                            .long("keep-gb")
+                            .help("Remove the least recently used build cache until it is at most this many GB")
+                            .takes_value(true)
+                            .validator(whole_number_validator),
+                    )
+                    .group(
+                        ArgGroup::with_name("policy")
+                            .args(&["older-than", "keep-gb"])
+                            .required(true),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("auto-prune")
+                    .about("Prune the cache before a ship when the instance is low on space (off by default)")
+                    .arg(
+                        Arg::with_name("min-free-gb")
+                            .long("min-free-gb")
+                            .help("Prune when less than this many GB is free")
+                            .takes_value(true)
+                            .validator(whole_number_validator),
+                    )
+                    .arg(
+                        Arg::with_name("disable")
+                            .long("disable")
+                            .help("Never prune the cache automatically"),
+                    )
+                    .group(
+                        ArgGroup::with_name("policy")
+                            .args(&["min-free-gb", "disable"])
+                            .required(true),
+                    ),
+            )
+    }
+
+    fn command_name(&self) -> &'static str {
+        "cache"
+    }
+
+    async fn run_fn(&self, matches: &ArgMatches<'_>) {
+        if matches.subcommand_matches("usage").is_some() {
+            match cache_usage().await {
+                Ok(usage) => {
+                    for line in usage.docker {
+                        println!("{}", line);
+                    }
+                    println!("\nDisk: {}", usage.disk);
+                }
+                Err(err) => println!("Failed to get cache usage with error: {:#?}", err),
+            }
+        }
+
+        if let Some(prune_matches) = matches.subcommand_matches("prune") {
+            // clap has validated the numbers & checked one of them was given
+            let policy = match prune_matches.value_of("older-than") {
+                Some(hours) => PrunePolicy::UnusedFor(hours.parse().unwrap()),
+                None => {
+                    PrunePolicy::KeepGb(prune_matches.value_of("keep-gb").unwrap().parse().unwrap())
+                }
+            };
+
+            match prune_cache(policy).await {
+                Ok(report) => {
+                    for line in report.output {
+                        println!("{}", line);
+                    }
+                    println!("\nBefore: {}\nAfter: {}", report.before, report.after);
+                }
+                Err(err) => println!("Failed to prune the cache with error: {:#?}", err),
+            }
+        }
+
+        if let Some(auto_matches) = matches.subcommand_matches("auto-prune") {
+            let min_free_gb = auto_matches
+                .value_of("min-free-gb")
+                .map(|gb| gb.parse::<u64>().unwrap());
+
+            match (set_auto_prune(min_free_gb), min_free_gb) {
+                (Ok(()), Some(gb)) => println!(
+                    "The cache will be pruned before a ship when less than {} GB is free",
+                    gb
+                ),
+                (Ok(()), None) => println!("Automatic pruning disabled"),
+                (Err(err), _) => {
+                    println!("Failed to set automatic pruning with error: {:#?}", err)
+                }
+            }
+        }
+    }
+}
+
+fn whole_number_validator(number: String) -> Result<(), String> {
+    match number.parse::<u32>() {
+        Ok(number) if number > 0 => Ok(()),
+        _ => Err(format!("{} must be a whole number greater than 0", number)),
+    }
+}
@@ -15,7 +15,7 @@ impl ConnectCommand {
 impl CLICommand for ConnectCommand {
     fn subcommand(&self) -> App<'_, '_> {
         SubCommand::with_name("connect")
-            .about("Create command which will ssh into box")
+            .about("Create command which will open a shell on the box")
             .usage("$(builder connect)")
     }
 
@@ -24,6 +24,6 @@ impl CLICommand for ConnectCommand {
     }
 
     async fn run_fn(&self, _matches: &ArgMatches<'_>) {
-        println!("{}", run_connect());
+        println!("{}", run_connect().await);
     }
 }
@@ -0,0 +1,69 @@
+use super::CLICommand;
+use crate::subcommands::set_idle_shutdown;
+use clap::{App, Arg, ArgGroup, ArgMatches, SubCommand};
+
+pub struct IdleShutdownCommand {}
+
+impl IdleShutdownCommand {
+    pub fn new() -> Self {
+        IdleShutdownCommand {}
+    }
+}
+
+#[async_trait::async_trait]
+impl CLICommand for IdleShutdownCommand {
+    fn subcommand(&self) -> App<'_, '_> {
+        SubCommand::with_name("idle-shutdown")
+            .about("Stop the instance automatically once no builds have run for a while")
+            .arg(
+                Arg::with_name("minutes")
+                    .long("minutes")
+                    .short("m")
+                    .help("Minutes since the last build after which the instance stops itself")
+                    .takes_value(true)
+                    .validator(minutes_validator),
+            )
+            .arg(
+                Arg::with_name("disable")
+                    .long("disable")
+                    .help("Remove the idle shutdown policy from the instance"),
+            )
+            .group(
+                ArgGroup::with_name("policy")
+                    .args(&["minutes", "disable"])
+                    .required(true),
+            )
+    }
+
+    fn command_name(&self) -> &'static str {
+        "idle-shutdown"
+    }
+
+    async fn run_fn(&self, matches: &ArgMatches<'_>) {
+        let minutes = matches
+            .value_of("minutes")
+            .map(|minutes| minutes.parse::<u32>().unwrap());
+
+        let result = set_idle_shutdown(minutes).await;
+        match (result, minutes) {
+            (Ok(()), Some(minutes)) => println!(
+                "Instance will stop after {} minutes without a build",
+                minutes
+            ),
+            (Ok(()), None) => println!("Idle shutdown disabled"),
+            (Err(err), _) => {
+                println!("Failed to set idle shutdown with error: {:#?}", err);
+            }
+        }
+    }
+}
+
+fn minutes_validator(minutes: String) -> Result<(), String> {
+    match minutes.parse::<u32>() {
+        Ok(minutes) if minutes > 0 => Ok(()),
+        _ => Err(format!(
+            "Minutes ({}) must be a whole number greater than 0",
+            minutes
+        )),
+    }
+}
@@ -1,17 +1,29 @@
 mod add_account;
+mod allow_ip;
 mod bootstrap;
+mod cache;
 mod connect;
+mod idle_shutdown;
+mod reconfigure;
 mod ship;
 mod start;
+mod status;
 mod stop;
+mod template;
 mod uninstall;
 
 pub use add_account::AddAccountCommand;
+pub use allow_ip::AllowIpCommand;
 pub use bootstrap::BootstrapCommand;
+pub use cache::CacheCommand;
 pub use connect::ConnectCommand;
+pub use idle_shutdown::IdleShutdownCommand;
+pub use reconfigure::ReconfigureCommand;
 pub use ship::ShipCommand;
 pub use start::StartCommand;
+pub use status::StatusCommand;
 pub use stop::StopCommand;
+pub use template::TemplateCommand;
 pub use uninstall::UninstallCommand;
 
 use clap::{App, ArgMatches};
@@ -0,0 +1,131 @@
+use super::bootstrap::volume_size_validator;
+use super::CLICommand;
+use crate::subcommands::{run_reconfigure, AmiChoice, Reconfiguration};
+use crate::{
+    cidr_parser, cidr_validator, tag_parser, tags_validator, transport_parser, transport_validator,
+    Tag,
+};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use std::path::Path;
+
+pub struct ReconfigureCommand {}
+
+impl ReconfigureCommand {
+    pub fn new() -> Self {
+        ReconfigureCommand {}
+    }
+}
+
+#[async_trait::async_trait]
+impl CLICommand for ReconfigureCommand {
+    fn subcommand(&self) -> App<'_, '_> {
+        SubCommand::with_name("reconfigure")
+            .about("Change the settings of the builder instance")
+            .long_about(
+                "Change the settings of the builder instance by updating the container-builder \
+stack. Only the settings which are passed are changed. Changing the AMI or root volume replaces \
+the instance, which also clears its Docker cache.",
+            )
+            .arg(
+                Arg::with_name("instance_type")
+                    .long("instance-type")
+                    .help("EC2 instance type of the builder, e.g. t3.large or t4g.large")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("volume_size")
+                    .long("volume-size")
+                    .help("Size of the root volume in GiB")
+                    .takes_value(true)
+                    .validator(volume_size_validator),
+            )
+            .arg(
+                Arg::with_name("volume_type")
+                    .long("volume-type")
+                    .help("EBS volume type of the root volume")
+                    .takes_value(true)
+                    .possible_values(&["gp2", "gp3", "standard"]),
+            )
+            .arg(
+                Arg::with_name("ami")
+                    .long("ami")
+                    .help("AMI ID to run, or 'latest' for the newest Amazon Linux 2 image")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("allow_cidr")
+                    .long("allow-cidr")
+                    .help("IPv4 range allowed to connect to the builder over SSH. Can be repeated")
+                    .takes_value(true)
+                    .number_of_values(1)
+                    .multiple(true)
+                    .validator(cidr_validator),
+            )
+            .arg(
+                Arg::with_name("transport")
+                    .long("transport")
+                    .help("How commands reach the instance: ssh or ssm")
+                    .takes_value(true)
+                    .validator(transport_validator),
+            )
+            .arg(
+                Arg::with_name("template")
+                    .long("template")
+                    .help("Path to an instance CloudFormation template to replace the stack's current one")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("tags")
+                    .long("tags")
+                    .help("Replace the tags on the builder's AWS resources")
+                    .long_help(
+                        "Replace the tags on the builder's AWS resources. Usage should be: 
+--tags Key=Value Key2=Value2 OR --tags Key=Value,Key2=Value2",
+                    )
+                    .takes_value(true)
+                    .multiple(true) // NOTE: Don't put positional args after this
+                    .validator(tags_validator),
+            )
+    }
+
+    fn command_name(&self) -> &'static str {
+        "reconfigure"
+    }
+
+    async fn run_fn(&self, matches: &ArgMatches<'_>) {
+        let changes = Reconfiguration {
+            instance_type: matches.value_of("instance_type").map(|t| t.to_owned()),
+            volume_size: matches
+                .value_of("volume_size")
+                .map(|size| size.parse().unwrap()),
+            volume_type: matches.value_of("volume_type").map(|t| t.to_owned()),
+            ami: matches.value_of("ami").map(|ami| match ami {
+                "latest" => AmiChoice::Latest,
+                ami => AmiChoice::Id(ami.to_owned()),
+            }),
+            allowed_cidrs: matches
+                .values_of("allow_cidr")
+                .map(|cidrs| cidrs.map(|cidr| cidr_parser(cidr).unwrap()).collect()),
+            tags: matches
+                .values_of("tags")
+                .map(|values| values.flat_map(parse_tags).collect()),
+            transport: matches
+                .value_of("transport")
+                .map(|transport| transport_parser(transport).unwrap()),
+        };
+        let template_path = matches.value_of("template").map(Path::new);
+
+        let result = run_reconfigure(changes, template_path).await;
+        match result {
+            Ok(true) => println!("Successfully reconfigured builder"),
+            Ok(false) => println!("Builder already has these settings"),
+            Err(err) => {
+                println!("Failed to reconfigure builder with error: {:#?}", err);
+            }
+        }
+    }
+}
+
+fn parse_tags(data: &str) -> Vec<Tag> {
+    tag_parser(data.to_owned()).unwrap_or_default()
+}
@@ -1,6 +1,8 @@
 use super::CLICommand;
-use crate::subcommands::ship;
+use crate::registry_uri_validator;
+use crate::subcommands::{ship, BuildOptions, RepositorySettings, ShipError};
 use clap::{App, Arg, ArgMatches, SubCommand};
+use std::fs::read_to_string;
 
 pub struct ShipCommand {}
 
@@ -13,7 +15,7 @@ impl ShipCommand {
 #[async_trait::async_trait]
 impl CLICommand for ShipCommand {
     fn subcommand(&self) -> App<'_, '_> {
-        let ship_help = "Zip up the current directory, send to instance, build docker image and push to container registry";
+        let ship_help = "Zip up the current directory, send to instance, build docker image and push to container registry. The instance is started first if it is stopped";
         SubCommand::with_name("ship")
             .about(ship_help)
             .arg(
@@ -28,17 +30,118 @@ impl CLICommand for ShipCommand {
                 Arg::with_name("registry")
                     .long("registry")
                     .short("r")
-                    .help("The registry URI where we want to push the container")
+                    .help("The registry URI where we want to push the container. Can be repeated")
+                    .long_help(
+                        "The registry URI where we want to push the container. Can be repeated to \
+push the same image to several registries. ECR URIs look like \
+<account>.dkr.ecr.<region>.amazonaws.com/<repository> and are logged in to with the push role of \
+the account. Other registries look like <host>/<repository> and use credentials from \
+CBUILDER_REGISTRY_<HOST>_USERNAME & _PASSWORD, ~/.cbuilder/registry-credentials.yml or the docker \
+config. A URI ending in :<tag> is pushed with only \
+that tag instead of the ones from --tag",
+                    )
                     .takes_value(true)
+                    .validator(registry_uri_validator)
+                    .number_of_values(1)
+                    .multiple(true)
                     .required(true),
             )
             .arg(
                 Arg::with_name("tag")
                     .long("tag")
                     .short("t")
-                    .help("Docker tag to apply to the build")
+                    .help("Docker tag to apply to the build. Can be repeated")
+                    .takes_value(true)
+                    .number_of_values(1)
+                    .multiple(true)
                     .default_value("latest"),
             )
+            .arg(
+                Arg::with_name("tag_from_git")
+                    .long("tag-from-git")
+                    .help("Also tag the build from the git state of --path")
+                    .long_help(
+                        "Also tag the build with the short SHA of HEAD, the branch name and the git \
+tag pointing at HEAD if there is one. Each gets a -dirty suffix when the working tree has \
+uncommitted changes. Tags passed with --tag are applied too; without any the default latest tag \
+is not used.",
+                    ),
+            )
+            .arg(
+                Arg::with_name("sync")
+                    .long("sync")
+                    .help("Only send files which changed since the last sync of this directory")
+                    .long_help(
+                        "Keep a copy of the directory on the instance and only send files which \
+changed since the last sync, along with a list of deleted files. Ships of the same directory \
+should not run in parallel when using this option.",
+                    ),
+            )
+            .arg(
+                Arg::with_name("build_arg")
+                    .long("build-arg")
+                    .help("A KEY=VALUE build-time variable. Can be repeated")
+                    .takes_value(true)
+                    .number_of_values(1)
+                    .multiple(true)
+                    .validator(key_value_validator),
+            )
+            .arg(
+                Arg::with_name("target")
+                    .long("target")
+                    .help("The build stage to build")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("file")
+                    .long("file")
+                    .help("Path to the Dockerfile, relative to --path")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("platform")
+                    .long("platform")
+                    .help("The platform to build for, e.g. linux/arm64")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("label")
+                    .long("label")
+                    .help("A KEY=VALUE label for the image. Can be repeated")
+                    .takes_value(true)
+                    .number_of_values(1)
+                    .multiple(true)
+                    .validator(key_value_validator),
+            )
+            .arg(
+                Arg::with_name("no_cache")
+                    .long("no-cache")
+                    .help("Don't use the builder's cache when building the image"),
+            )
+            .arg(
+                Arg::with_name("create_repo")
+                    .long("create-repo")
+                    .help("Create ECR repositories which don't exist before building"),
+            )
+            .arg(
+                Arg::with_name("scan_on_push")
+                    .long("scan-on-push")
+                    .requires("create_repo")
+                    .help("Scan images pushed to repositories created by --create-repo"),
+            )
+            .arg(
+                Arg::with_name("immutable_tags")
+                    .long("immutable-tags")
+                    .requires("create_repo")
+                    .help("Make tags immutable in repositories created by --create-repo"),
+            )
+            .arg(
+                Arg::with_name("lifecycle_policy")
+                    .long("lifecycle-policy")
+                    .requires("create_repo")
+                    .takes_value(true)
+                    .help("Path to a lifecycle policy JSON file for repositories created by --create-repo"),
+            )
             .arg(
                 Arg::with_name("build_args")
                     .last(true)
@@ -54,21 +157,94 @@ impl CLICommand for ShipCommand {
 
     async fn run_fn(&self, matches: &ArgMatches<'_>) {
         let path = matches.value_of("path").unwrap().to_owned();
-        let registry_uri = matches.value_of("registry").unwrap().to_owned();
-        let additional_args: Option<Vec<String>> = matches
-            .values_of("build_args")
-            .map(|values| values.map(|value| value.to_owned()).collect());
-        let tag = matches.value_of("tag").unwrap().to_owned();
+        let registry_uris: Vec<String> = matches
+            .values_of("registry")
+            .unwrap()
+            .map(|uri| uri.to_owned())
+            .collect();
+        let values = |name: &str| -> Vec<String> {
+            matches
+                .values_of(name)
+                .map(|values| values.map(|value| value.to_owned()).collect())
+                .unwrap_or_default()
+        };
+        let build_options = BuildOptions {
+            build_args: values("build_arg"),
+            target: matches.value_of("target").map(|value| value.to_owned()),
+            file: matches.value_of("file").map(|value| value.to_owned()),
+            platform: matches.value_of("platform").map(|value| value.to_owned()),
+            labels: values("label"),
+            no_cache: matches.is_present("no_cache"),
+            extra_args: values("build_args"),
+        };
+        let tag_from_git = matches.is_present("tag_from_git");
+        // The default tag is only used when the tags aren't coming from git
+        let tags: Vec<String> = if tag_from_git && matches.occurrences_of("tag") == 0 {
+            vec![]
+        } else {
+            matches
+                .values_of("tag")
+                .unwrap()
+                .map(|tag| tag.to_owned())
+                .collect()
+        };
+        let sync = matches.is_present("sync");
+
+        let lifecycle_policy = match matches.value_of("lifecycle_policy").map(read_to_string) {
+            Some(Ok(policy)) => Some(policy),
+            Some(Err(err)) => {
+                println!("ship failed: could not read the lifecycle policy: {}", err);
+                return;
+            }
+            None => None,
+        };
+        let create_repo = if matches.is_present("create_repo") {
+            Some(RepositorySettings {
+                scan_on_push: matches.is_present("scan_on_push"),
+                immutable_tags: matches.is_present("immutable_tags"),
+                lifecycle_policy,
+            })
+        } else {
+            None
+        };
 
-        let result = ship(path, registry_uri, additional_args, tag);
+        let result = ship(
+            path,
+            registry_uris,
+            build_options,
+            tags,
+            tag_from_git,
+            sync,
+            create_repo,
+        )
+        .await;
         // ship subcommand
         match result {
             Ok(()) => {
                 println!("Ship was successful");
             }
+            Err(ShipError::ScriptExitCodeError {
+                exit_status,
+                output_tail,
+            }) => {
+                println!(
+                    "ship failed: build script exited with status {}. Last output:",
+                    exit_status
+                );
+                for line in output_tail {
+                    println!("    {}", line);
+                }
+            }
             Err(err) => {
                 println!("ship failed with error: {:#?}", err);
             }
         }
     }
 }
+
+fn key_value_validator(value: String) -> Result<(), String> {
+    match value.split_once('=') {
+        Some((key, _)) if !key.is_empty() => Ok(()),
+        _ => Err(format!("{} must be in the form KEY=VALUE", value)),
+    }
+}
@@ -0,0 +1,40 @@
+use super::CLICommand;
+use crate::subcommands::get_status;
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+pub struct StatusCommand {}
+
+impl StatusCommand {
+    pub fn new() -> Self {
+        StatusCommand {}
+    }
+}
+
+#[async_trait::async_trait]
+impl CLICommand for StatusCommand {
+    fn subcommand(&self) -> App<'_, '_> {
+        SubCommand::with_name("status")
+            .about("Show the state of the stacks, instance, accounts and local config")
+            .arg(
+                Arg::with_name("json")
+                    .long("json")
+                    .help("Print the status as JSON"),
+            )
+    }
+
+    fn command_name(&self) -> &'static str {
+        "status"
+    }
+
+    async fn run_fn(&self, matches: &ArgMatches<'_>) {
+        let result = get_status().await;
+
+        match result {
+            Ok(report) if matches.is_present("json") => println!("{}", report.to_json()),
+            Ok(report) => println!("{}", report.to_text()),
+            Err(err) => {
+                println!("Failed to get status with error: {:?}", err);
+            }
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use super::CLICommand;
+use crate::Template;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+pub struct TemplateCommand {}
+
+impl TemplateCommand {
+    pub fn new() -> Self {
+        TemplateCommand {}
+    }
+}
+
+#[async_trait::async_trait]
+impl CLICommand for TemplateCommand {
+    fn subcommand(&self) -> App<'_, '_> {
+        SubCommand::with_name("template")
+            .about("Inspect the CloudFormation templates built into this binary")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                SubCommand::with_name("print")
+                    .about("Print a built in template, e.g. as a starting point for --template")
+                    .arg(
+                        Arg::with_name("name")
+                            .help("The template to print")
+                            .possible_values(&Template::names())
+                            .required(true),
+                    ),
+            )
+    }
+
+    fn command_name(&self) -> &'static str {
+        "template"
+    }
+
+    async fn run_fn(&self, matches: &ArgMatches<'_>) {
+        if let Some(print_matches) = matches.subcommand_matches("print") {
+            // clap has already checked the name is one of the templates
+            let template = Template::from_name(print_matches.value_of("name").unwrap()).unwrap();
+            print!("{}", template.embedded());
+        }
+    }
+}
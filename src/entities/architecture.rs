@@ -0,0 +1,38 @@
+/// The CPU architecture of the builder instance. arm64 is used by Graviton instance types.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Architecture {
+    X86_64,
+    Arm64,
+}
+
+impl Architecture {
+    /// The name EC2 uses for the architecture in AMI names and instance type descriptions
+    pub fn name(self) -> &'static str {
+        match self {
+            Architecture::X86_64 => "x86_64",
+            Architecture::Arm64 => "arm64",
+        }
+    }
+}
+
+pub fn architecture_validator(maybe_architecture: String) -> Result<(), String> {
+    architecture_parser(&maybe_architecture).map(|_| ())
+}
+
+pub fn architecture_parser(maybe_architecture: &str) -> Result<Architecture, String> {
+    match maybe_architecture {
+        "x86_64" => Ok(Architecture::X86_64),
+        "arm64" => Ok(Architecture::Arm64),
+        _ => Err(format!(
+            "Architecture ({}) is not valid. Use x86_64 or arm64",
+            maybe_architecture
+        )),
+    }
+}
+
+#[test]
+fn architecture_parser_accepts_ec2_names() {
+    assert_eq!(architecture_parser("x86_64"), Ok(Architecture::X86_64));
+    assert_eq!(architecture_parser("arm64"), Ok(Architecture::Arm64));
+    assert!(architecture_parser("aarch64").is_err());
+}
@@ -0,0 +1,101 @@
+use crate::CredentialsChain;
+use rusoto_cloudformation::CloudFormationClient;
+use rusoto_core::credential::AutoRefreshingProvider;
+use rusoto_core::{HttpClient, Region};
+use rusoto_ec2::Ec2Client;
+use rusoto_s3::S3Client;
+use rusoto_ssm::SsmClient;
+use rusoto_sts::StsClient;
+
+/// Creates the rusoto clients for a single profile & region.
+///
+/// Clients created from the same factory share one credentials cache so a command only
+/// resolves (and possibly assumes a role for) each profile once.
+#[derive(Clone)]
+pub struct AwsClientFactory {
+    profile: String,
+    region: Region,
+    credentials: AutoRefreshingProvider<CredentialsChain>,
+}
+
+impl AwsClientFactory {
+    /// Clients for the builder's own profile, which credentials in the environment override
+    pub fn new(profile: String, region: Region) -> AwsClientFactory {
+        let chain = CredentialsChain::new(profile.clone(), region.clone());
+        AwsClientFactory::new_with_chain(profile, region, chain)
+    }
+
+    /// Clients for a profile which must be used as given, such as one passed with `--profile`
+    /// or belonging to another account
+    pub fn with_explicit_profile(profile: String, region: Region) -> AwsClientFactory {
+        let chain = CredentialsChain::with_explicit_profile(profile.clone(), region.clone());
+        AwsClientFactory::new_with_chain(profile, region, chain)
+    }
+
+    fn new_with_chain(
+        profile: String,
+        region: Region,
+        chain: CredentialsChain,
+    ) -> AwsClientFactory {
+        let credentials =
+            AutoRefreshingProvider::new(chain).expect("Failed to create credentials provider");
+
+        AwsClientFactory {
+            profile,
+            region,
+            credentials,
+        }
+    }
+
+    pub fn get_profile(&self) -> String {
+        self.profile.clone()
+    }
+
+    pub fn get_region(&self) -> Region {
+        self.region.clone()
+    }
+
+    pub fn cloudformation(&self) -> CloudFormationClient {
+        CloudFormationClient::new_with(
+            create_dispatcher(),
+            self.credentials.clone(),
+            self.region.clone(),
+        )
+    }
+
+    pub fn ec2(&self) -> Ec2Client {
+        Ec2Client::new_with(
+            create_dispatcher(),
+            self.credentials.clone(),
+            self.region.clone(),
+        )
+    }
+
+    pub fn s3(&self) -> S3Client {
+        S3Client::new_with(
+            create_dispatcher(),
+            self.credentials.clone(),
+            self.region.clone(),
+        )
+    }
+
+    pub fn ssm(&self) -> SsmClient {
+        SsmClient::new_with(
+            create_dispatcher(),
+            self.credentials.clone(),
+            self.region.clone(),
+        )
+    }
+
+    pub fn sts(&self) -> StsClient {
+        StsClient::new_with(
+            create_dispatcher(),
+            self.credentials.clone(),
+            self.region.clone(),
+        )
+    }
+}
+
+fn create_dispatcher() -> HttpClient {
+    HttpClient::new().expect("Failed to create request dispatcher")
+}
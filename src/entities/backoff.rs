@@ -0,0 +1,60 @@
+use std::time::{Duration, Instant};
+
+/// Exponential backoff between polls of something we are waiting on, giving up after a timeout
+pub struct Backoff {
+    delay: Duration,
+    max_delay: Duration,
+    deadline: Instant,
+}
+
+impl Backoff {
+    pub fn new(initial_delay: Duration, max_delay: Duration, timeout: Duration) -> Backoff {
+        Backoff {
+            delay: initial_delay,
+            max_delay,
+            deadline: Instant::now() + timeout,
+        }
+    }
+
+    /// The time to wait before the next attempt or `None` if the timeout would be passed
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        let now = Instant::now();
+        if now >= self.deadline {
+            return None;
+        }
+
+        let delay = self.delay.min(self.deadline - now);
+        self.delay = (self.delay * 2).min(self.max_delay);
+        Some(delay)
+    }
+}
+
+#[test]
+fn backoff_doubles_up_to_the_max_delay() {
+    let mut backoff = Backoff::new(
+        Duration::from_secs(1),
+        Duration::from_secs(5),
+        Duration::from_secs(600),
+    );
+
+    let delays: Vec<Duration> = (0..5).filter_map(|_| backoff.next_delay()).collect();
+
+    assert_eq!(
+        delays,
+        vec![1, 2, 4, 5, 5]
+            .into_iter()
+            .map(Duration::from_secs)
+            .collect::<Vec<Duration>>()
+    );
+}
+
+#[test]
+fn backoff_stops_after_the_timeout() {
+    let mut backoff = Backoff::new(
+        Duration::from_secs(1),
+        Duration::from_secs(5),
+        Duration::from_secs(0),
+    );
+
+    assert_eq!(backoff.next_delay(), None);
+}
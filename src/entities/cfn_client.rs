@@ -1,9 +1,10 @@
-use crate::Tag;
+use crate::{AwsClientFactory, Tag};
 use rusoto_cloudformation::{
     CloudFormation, CloudFormationClient, CreateStackError, CreateStackInput, DeleteStackInput,
-    DescribeStackEventsInput, DescribeStackResourceInput, Parameter, UpdateStackInput,
+    DescribeStackEventsInput, DescribeStackResourceInput, DescribeStacksInput, Parameter,
+    UpdateStackInput,
 };
-use rusoto_core::{credential::ProfileProvider, HttpClient, Region, RusotoError};
+use rusoto_core::RusotoError;
 use std::{
     thread::sleep,
     time::{Duration, Instant},
@@ -13,6 +14,8 @@ use std::{
 pub enum DeployError {
     CreateStackFailed,
     DescribeStackFailed,
+    UpdateStackFailed(String),
+    UpdateRolledBack(String),
     TimedOut,
 }
 
@@ -40,34 +43,33 @@ pub struct CfnClient {
 }
 
 impl CfnClient {
-    pub fn new(profile_name: String) -> CfnClient {
-        let home_dir = dirs::home_dir().expect("Could not find home directory");
-
-        let profile_provider =
-            ProfileProvider::with_configuration(home_dir.join(".aws/credentials"), profile_name);
+    pub fn new(aws: &AwsClientFactory) -> CfnClient {
+        CfnClient {
+            client: aws.cloudformation(),
+        }
+    }
 
-        // Create cloudformation client
-        let client = CloudFormationClient::new_with(
-            HttpClient::new().expect("Failed to create request dispatcher"),
-            profile_provider.clone(),
-            Region::UsEast1,
-        );
+    pub async fn get_instance_id(&self) -> Option<String> {
+        self.get_resource_id("Instance").await
+    }
 
-        CfnClient { client }
+    /// Get the name of the bucket used to move files to the instance over SSM
+    pub async fn get_staging_bucket(&self) -> Option<String> {
+        self.get_resource_id("StagingBucket").await
     }
 
-    pub async fn get_instance_id(&self) -> Option<String> {
+    async fn get_resource_id(&self, logical_resource_id: &str) -> Option<String> {
         // Describe the stack
         let result = self
             .client
             .describe_stack_resource(DescribeStackResourceInput {
                 stack_name: "container-builder".to_owned(),
-                logical_resource_id: "Instance".to_owned(),
+                logical_resource_id: logical_resource_id.to_owned(),
             })
             .await
             .ok()?;
 
-        // Find the ID of the instance
+        // Find the physical ID of the resource
         let result = result
             .stack_resource_detail
             .map(|t| t.physical_resource_id)
@@ -76,6 +78,52 @@ impl CfnClient {
         Some(result)
     }
 
+    /// Get the status of a stack, e.g. CREATE_COMPLETE. Returns None if the stack doesn't exist.
+    pub async fn get_stack_status(&self, stack_name: String) -> Result<Option<String>, String> {
+        let result = self
+            .client
+            .describe_stacks(DescribeStacksInput {
+                stack_name: Some(stack_name),
+                next_token: None,
+            })
+            .await;
+
+        match result {
+            Ok(output) => Ok(output
+                .stacks
+                .and_then(|stacks| stacks.into_iter().next())
+                .map(|stack| stack.stack_status)),
+            // CloudFormation reports missing stacks as a validation error
+            Err(RusotoError::Unknown(response))
+                if response.body_as_str().contains("does not exist") =>
+            {
+                Ok(None)
+            }
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    async fn get_stack_parameter_keys(&self, stack_name: &str) -> Option<Vec<String>> {
+        let result = self
+            .client
+            .describe_stacks(DescribeStacksInput {
+                stack_name: Some(stack_name.to_owned()),
+                next_token: None,
+            })
+            .await
+            .ok()?;
+
+        let parameters = result.stacks?.into_iter().next()?.parameters;
+
+        Some(
+            parameters
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|parameter| parameter.parameter_key)
+                .collect(),
+        )
+    }
+
     pub async fn delete_stack(&self, stack_name: String) -> bool {
         let result = self
             .client
@@ -91,28 +139,23 @@ impl CfnClient {
     }
 
     pub async fn update_stack(&self, accounts: String) -> bool {
+        // Stacks created by older versions have fewer parameters so keep whatever exists
+        let parameter_keys = match self.get_stack_parameter_keys("container-builder").await {
+            Some(keys) => keys,
+            None => return false,
+        };
+
+        let parameters = merge_parameters(
+            parameter_keys,
+            &[SimpleParameter::new("AccountRoles".to_owned(), accounts)],
+        );
+
         let deploy_result = self
             .client
             .update_stack(UpdateStackInput {
                 capabilities: Some(vec!["CAPABILITY_NAMED_IAM".to_owned()]),
                 stack_name: "container-builder".to_owned(),
-                parameters: Some(vec![
-                    Parameter {
-                        parameter_key: Some("AmiId".to_owned()),
-                        use_previous_value: Some(true),
-                        ..Parameter::default()
-                    },
-                    Parameter {
-                        parameter_key: Some("SSHKeyName".to_owned()),
-                        use_previous_value: Some(true),
-                        ..Parameter::default()
-                    },
-                    Parameter {
-                        parameter_key: Some("AccountRoles".to_owned()),
-                        parameter_value: Some(accounts),
-                        ..Parameter::default()
-                    },
-                ]),
+                parameters: Some(parameters),
                 use_previous_template: Some(true),
                 ..UpdateStackInput::default()
             })
@@ -121,6 +164,72 @@ impl CfnClient {
         deploy_result.is_ok()
     }
 
+    /// Update the stack, changing the given parameters and keeping the previous values of the
+    /// rest. Without a `template` the stack keeps the one it has. Returns false if there was
+    /// nothing to update.
+    pub async fn reconfigure_stack(
+        &self,
+        stack_name: String,
+        template: Option<String>,
+        changes: &[SimpleParameter],
+        tags: Option<&[Tag]>,
+        timeout: u64,
+    ) -> Result<bool, DeployError> {
+        let parameter_keys = self
+            .get_stack_parameter_keys(&stack_name)
+            .await
+            .ok_or(DeployError::DescribeStackFailed)?;
+
+        let update_result = self
+            .client
+            .update_stack(create_update_input(
+                stack_name.clone(),
+                template,
+                merge_parameters(parameter_keys, changes),
+                tags,
+            ))
+            .await;
+
+        match update_result {
+            Ok(_) => {}
+            // CloudFormation reports an update which changes nothing as a validation error
+            Err(RusotoError::Unknown(response))
+                if response
+                    .body_as_str()
+                    .contains("No updates are to be performed") =>
+            {
+                return Ok(false);
+            }
+            Err(err) => return Err(DeployError::UpdateStackFailed(err.to_string())),
+        }
+
+        let start_time = Instant::now();
+
+        // Events from previous updates are still around so poll the stack status instead
+        loop {
+            let status = self
+                .get_stack_status(stack_name.clone())
+                .await
+                .map_err(|_| DeployError::DescribeStackFailed)?
+                .ok_or(DeployError::DescribeStackFailed)?;
+
+            if status == "UPDATE_COMPLETE" {
+                return Ok(true);
+            }
+
+            if !status.ends_with("_IN_PROGRESS") {
+                return Err(DeployError::UpdateRolledBack(status));
+            }
+
+            // Check for timeout
+            if start_time.elapsed() > Duration::from_secs(timeout) {
+                return Err(DeployError::TimedOut);
+            }
+
+            sleep(Duration::from_secs(5));
+        }
+    }
+
     pub async fn deploy_stack(
         &self,
         stack_name: String,
@@ -207,6 +316,42 @@ async fn has_stack_completed<Client: CloudFormation>(
     Ok(created_stack)
 }
 
+/// Without a template the stack keeps its current one
+fn create_update_input(
+    stack_name: String,
+    template: Option<String>,
+    parameters: Vec<Parameter>,
+    tags: Option<&[Tag]>,
+) -> UpdateStackInput {
+    UpdateStackInput {
+        capabilities: Some(vec!["CAPABILITY_NAMED_IAM".to_owned()]),
+        stack_name,
+        parameters: Some(parameters),
+        tags: tags.map(|tags| tags.iter().map(|tag| convert_tag(tag.clone())).collect()),
+        use_previous_template: Some(template.is_none()),
+        template_body: template,
+        ..UpdateStackInput::default()
+    }
+}
+
+/// Keep the previous value of every existing parameter except those being changed
+fn merge_parameters(existing_keys: Vec<String>, changes: &[SimpleParameter]) -> Vec<Parameter> {
+    existing_keys
+        .into_iter()
+        .filter(|key| !changes.iter().any(|change| &change.key == key))
+        .map(|key| Parameter {
+            parameter_key: Some(key),
+            use_previous_value: Some(true),
+            ..Parameter::default()
+        })
+        .chain(
+            changes
+                .iter()
+                .map(|change| convert_parameter(change.clone())),
+        )
+        .collect()
+}
+
 fn convert_tag(tag: Tag) -> rusoto_cloudformation::Tag {
     rusoto_cloudformation::Tag {
         key: tag.key,
@@ -221,3 +366,46 @@ fn convert_parameter(parameter: SimpleParameter) -> rusoto_cloudformation::Param
         ..rusoto_cloudformation::Parameter::default()
     }
 }
+
+#[test]
+fn merge_parameters_keeps_unchanged_values() {
+    let parameters = merge_parameters(
+        vec!["AmiId".to_owned(), "InstanceType".to_owned()],
+        &[SimpleParameter::new(
+            "InstanceType".to_owned(),
+            "t3.large".to_owned(),
+        )],
+    );
+
+    assert_eq!(
+        parameters,
+        vec![
+            Parameter {
+                parameter_key: Some("AmiId".to_owned()),
+                use_previous_value: Some(true),
+                ..Parameter::default()
+            },
+            Parameter {
+                parameter_key: Some("InstanceType".to_owned()),
+                parameter_value: Some("t3.large".to_owned()),
+                ..Parameter::default()
+            },
+        ]
+    );
+}
+
+#[test]
+fn updates_keep_the_stack_template_unless_one_is_given() {
+    let previous = create_update_input("container-builder".to_owned(), None, vec![], None);
+    assert_eq!(previous.use_previous_template, Some(true));
+    assert_eq!(previous.template_body, None);
+
+    let replaced = create_update_input(
+        "container-builder".to_owned(),
+        Some("Resources: {}".to_owned()),
+        vec![],
+        None,
+    );
+    assert_eq!(replaced.use_previous_template, Some(false));
+    assert_eq!(replaced.template_body, Some("Resources: {}".to_owned()));
+}
@@ -0,0 +1,36 @@
+use std::net::Ipv4Addr;
+
+pub fn cidr_validator(maybe_cidr: String) -> Result<(), String> {
+    cidr_parser(&maybe_cidr).map(|_| ())
+}
+
+/// Parse an IPv4 CIDR block such as 203.0.113.0/24, normalising a bare IP to a /32
+pub fn cidr_parser(maybe_cidr: &str) -> Result<String, String> {
+    let error = || format!("CIDR ({}) is not a valid IPv4 range", maybe_cidr);
+
+    let (ip, prefix) = match maybe_cidr.split_once('/') {
+        Some((ip, prefix)) => (ip, prefix.parse::<u8>().map_err(|_| error())?),
+        None => (maybe_cidr, 32),
+    };
+
+    let ip: Ipv4Addr = ip.parse().map_err(|_| error())?;
+    if prefix > 32 {
+        return Err(error());
+    }
+
+    Ok(format!("{}/{}", ip, prefix))
+}
+
+#[test]
+fn cidr_parser_accepts_ranges_and_single_ips() {
+    assert_eq!(cidr_parser("10.0.0.0/8"), Ok("10.0.0.0/8".to_owned()));
+    assert_eq!(cidr_parser("203.0.113.7"), Ok("203.0.113.7/32".to_owned()));
+}
+
+#[test]
+fn cidr_parser_rejects_invalid_ranges() {
+    assert!(cidr_parser("10.0.0.0/33").is_err());
+    assert!(cidr_parser("256.0.0.1/32").is_err());
+    assert!(cidr_parser("my-office").is_err());
+    assert!(cidr_parser("10.0.0.0/").is_err());
+}
@@ -1,7 +1,10 @@
-use serde::{Deserialize, Serialize};
+use crate::{region_parser, TransportKind};
+use rusoto_core::Region;
+use serde::{Deserialize, Deserializer, Serialize};
 use std::fs::File;
 use std::io::Write;
 use std::path::Path;
+use std::time::Duration;
 
 #[derive(Serialize, Deserialize, Debug)]
 struct Account {
@@ -13,7 +16,30 @@ struct Account {
 pub struct Config {
     instance_ip: String,
     base_profile: String,
+    #[serde(default = "default_region", deserialize_with = "deserialize_region")]
+    region: String,
     sub_accounts: Vec<Account>,
+    #[serde(default)]
+    idle_shutdown_minutes: Option<u32>,
+    #[serde(default)]
+    allowed_cidrs: Vec<String>,
+    #[serde(default)]
+    transport: TransportKind,
+    #[serde(default)]
+    ssh_connect_timeout_secs: Option<u64>,
+    #[serde(default)]
+    cache_min_free_gb: Option<u64>,
+}
+
+fn default_region() -> String {
+    Region::UsEast1.name().to_owned()
+}
+
+/// Reject unknown regions when the config is read rather than guessing one later
+fn deserialize_region<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let region = String::deserialize(deserializer)?;
+    region_parser(&region).map_err(serde::de::Error::custom)?;
+    Ok(region)
 }
 
 pub enum ConfigWriteError {
@@ -22,11 +48,17 @@ pub enum ConfigWriteError {
 }
 
 impl Config {
-    pub fn new(instance_ip: String, base_profile: String) -> Config {
+    pub fn new(instance_ip: String, base_profile: String, region: Region) -> Config {
         Config {
             instance_ip,
             base_profile,
+            region: region.name().to_owned(),
             sub_accounts: vec![],
+            idle_shutdown_minutes: None,
+            allowed_cidrs: vec![],
+            transport: TransportKind::Ssh,
+            ssh_connect_timeout_secs: None,
+            cache_min_free_gb: None,
         }
     }
 
@@ -38,6 +70,10 @@ impl Config {
         self.base_profile.clone()
     }
 
+    pub fn get_region(&self) -> Region {
+        region_parser(&self.region).expect("The region is checked when the config is read")
+    }
+
     pub fn get_account_numbers(&self) -> Vec<String> {
         self.sub_accounts
             .iter()
@@ -55,7 +91,7 @@ impl Config {
             self.sub_accounts.remove(index);
         }
 
-        // Push the
+        // Push the new account
         self.sub_accounts.push(Account {
             profile,
             account_no,
@@ -70,13 +106,11 @@ impl Config {
     }
 
     pub fn write_to_file(&self, path: &Path) -> Result<(), ConfigWriteError> {
-        let data =
-            serde_yaml::to_string(self).map_err(|err| ConfigWriteError::ParsingFailed(err))?;
-        let mut file: File =
-            File::create(path).map_err(|err| ConfigWriteError::FileOperationFailed(err))?;
+        let data = serde_yaml::to_string(self).map_err(ConfigWriteError::ParsingFailed)?;
+        let mut file: File = File::create(path).map_err(ConfigWriteError::FileOperationFailed)?;
 
         file.write_all(data.as_bytes())
-            .map_err(|err| ConfigWriteError::FileOperationFailed(err))?;
+            .map_err(ConfigWriteError::FileOperationFailed)?;
 
         Ok(())
     }
@@ -84,19 +118,63 @@ impl Config {
     pub fn read_from_file(path: &Path) -> Option<Config> {
         let data = std::fs::read_to_string(path).ok()?;
 
-        let config = serde_yaml::from_str(&data).ok()?;
-
-        Some(config)
+        // Printed to stderr as `connect`'s stdout is run by the shell
+        match serde_yaml::from_str(&data) {
+            Ok(config) => Some(config),
+            Err(err) => {
+                eprintln!("Failed to read {}: {}", path.display(), err);
+                None
+            }
+        }
     }
 
     pub fn set_instance_ip(&mut self, instance_ip: String) {
         self.instance_ip = instance_ip;
     }
+
+    pub fn get_idle_shutdown_minutes(&self) -> Option<u32> {
+        self.idle_shutdown_minutes
+    }
+
+    pub fn set_idle_shutdown_minutes(&mut self, minutes: Option<u32>) {
+        self.idle_shutdown_minutes = minutes;
+    }
+
+    pub fn get_allowed_cidrs(&self) -> Vec<String> {
+        self.allowed_cidrs.clone()
+    }
+
+    pub fn set_allowed_cidrs(&mut self, cidrs: Vec<String>) {
+        self.allowed_cidrs = cidrs;
+    }
+
+    pub fn get_transport(&self) -> TransportKind {
+        self.transport
+    }
+
+    pub fn set_transport(&mut self, transport: TransportKind) {
+        self.transport = transport;
+    }
+
+    /// Only set by editing the config, for networks where connecting is slow
+    pub fn get_ssh_connect_timeout(&self) -> Option<Duration> {
+        self.ssh_connect_timeout_secs.map(Duration::from_secs)
+    }
+
+    /// The free space on the instance below which the docker cache is pruned before a
+    /// build, or `None` if it is never pruned automatically
+    pub fn get_cache_min_free_gb(&self) -> Option<u64> {
+        self.cache_min_free_gb
+    }
+
+    pub fn set_cache_min_free_gb(&mut self, gb: Option<u64>) {
+        self.cache_min_free_gb = gb;
+    }
 }
 
 #[test]
 fn adding_a_duplicate_account_updates_the_profile() {
-    let mut config = Config::new("0.0.0.0".to_owned(), "base".to_owned());
+    let mut config = Config::new("0.0.0.0".to_owned(), "base".to_owned(), Region::UsEast1);
     config.add_account("profile2".to_owned(), "123".to_owned());
     assert_eq!(
         config.get_account_profile("123".to_owned()),
@@ -109,3 +187,24 @@ fn adding_a_duplicate_account_updates_the_profile() {
         Some("profile3".to_owned())
     );
 }
+
+#[test]
+fn config_without_region_defaults_to_us_east_1() {
+    let config: Config =
+        serde_yaml::from_str("instance_ip: 0.0.0.0\nbase_profile: base\nsub_accounts: []\n")
+            .unwrap();
+
+    assert_eq!(config.get_region(), Region::UsEast1);
+    assert_eq!(config.get_transport(), TransportKind::Ssh);
+    assert_eq!(config.get_cache_min_free_gb(), None);
+}
+
+#[test]
+fn config_with_an_unknown_region_is_rejected() {
+    let result = serde_yaml::from_str::<Config>(
+        "instance_ip: 0.0.0.0\nbase_profile: base\nregion: eu-wst-1\nsub_accounts: []\n",
+    );
+
+    let err = result.err().unwrap().to_string();
+    assert!(err.contains("Region (eu-wst-1) is not valid"), "{}", err);
+}
@@ -0,0 +1,167 @@
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The content hash of every file in a build context, keyed by the path relative to the
+/// context root. Comparing the manifest of the local directory with the one stored on the
+/// instance tells us which files need to be sent.
+#[derive(Debug, PartialEq)]
+pub struct ContextManifest {
+    files: BTreeMap<String, String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ManifestDiff {
+    pub changed: Vec<String>,
+    pub deleted: Vec<String>,
+}
+
+impl ContextManifest {
+    pub fn from_files(context_root: &Path, files: &[PathBuf]) -> io::Result<ContextManifest> {
+        let mut manifest = BTreeMap::new();
+
+        for file in files {
+            let relative = file
+                .strip_prefix(context_root)
+                .unwrap_or(file)
+                .to_string_lossy()
+                .into_owned();
+            manifest.insert(relative, hash_file(file)?);
+        }
+
+        Ok(ContextManifest { files: manifest })
+    }
+
+    /// Parse a manifest written by `to_manifest_string`. Unreadable lines are skipped which
+    /// means those files will be sent again.
+    pub fn parse(contents: &str) -> ContextManifest {
+        let files = contents
+            .lines()
+            .filter_map(|line| {
+                let (hash, path) = line.split_once('\t')?;
+                Some((path.to_owned(), hash.to_owned()))
+            })
+            .collect();
+
+        ContextManifest { files }
+    }
+
+    pub fn to_manifest_string(&self) -> String {
+        self.files
+            .iter()
+            .map(|(path, hash)| format!("{}\t{}\n", hash, path))
+            .collect()
+    }
+
+    /// Work out what needs to change to turn `remote` into this manifest
+    pub fn diff(&self, remote: &ContextManifest) -> ManifestDiff {
+        let changed = self
+            .files
+            .iter()
+            .filter(|(path, hash)| remote.files.get(*path) != Some(hash))
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        let deleted = remote
+            .files
+            .keys()
+            .filter(|path| !self.files.contains_key(*path))
+            .cloned()
+            .collect();
+
+        ManifestDiff { changed, deleted }
+    }
+}
+
+/// A stable name for a build context directory which is safe to use in remote paths.
+/// The hash of the full path keeps directories with the same name apart.
+pub fn context_name(context_root: &Path) -> io::Result<String> {
+    let full_path = context_root.canonicalize()?;
+    let dir_name: String = full_path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+        .chars()
+        .map(|ch| match ch {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '_' | '-' => ch,
+            _ => '_',
+        })
+        .collect();
+
+    let path_hash = to_hex(&Sha256::digest(full_path.to_string_lossy().as_bytes()));
+
+    Ok(format!("{}-{}", dir_name, &path_hash[..12]))
+}
+
+fn hash_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+
+    Ok(to_hex(&hasher.finalize()))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+fn manifest_of(files: &[(&str, &str)]) -> ContextManifest {
+    ContextManifest {
+        files: files
+            .iter()
+            .map(|(path, hash)| (path.to_string(), hash.to_string()))
+            .collect(),
+    }
+}
+
+#[test]
+fn manifest_round_trips_through_a_string() {
+    let manifest = manifest_of(&[("Dockerfile", "abc"), ("src/my file.sh", "def")]);
+
+    assert_eq!(
+        ContextManifest::parse(&manifest.to_manifest_string()),
+        manifest
+    );
+}
+
+#[test]
+fn diff_finds_changed_added_and_deleted_files() {
+    let local = manifest_of(&[("Dockerfile", "1"), ("src/a.sh", "2"), ("src/new.sh", "3")]);
+    let remote = manifest_of(&[
+        ("Dockerfile", "1"),
+        ("src/a.sh", "old"),
+        ("src/gone.sh", "4"),
+    ]);
+
+    assert_eq!(
+        local.diff(&remote),
+        ManifestDiff {
+            changed: vec!["src/a.sh".to_owned(), "src/new.sh".to_owned()],
+            deleted: vec!["src/gone.sh".to_owned()],
+        }
+    );
+}
+
+#[test]
+fn from_files_hashes_paths_relative_to_the_context() {
+    let root = Path::new("example_proj");
+    let manifest = ContextManifest::from_files(root, &[root.join("dependencies/file")]).unwrap();
+
+    assert_eq!(
+        manifest,
+        manifest_of(&[(
+            "dependencies/file",
+            "cdb4ee2aea69cc6a83331bbe96dc2caa9a299d21329efb0336fc02a82e1839a8"
+        )])
+    );
+}
+
+#[test]
+fn context_name_is_safe_for_remote_paths() {
+    let name = context_name(Path::new("example_proj")).unwrap();
+
+    assert!(name.starts_with("example_proj-"));
+    assert_eq!(name.len(), "example_proj-".len() + 12);
+}
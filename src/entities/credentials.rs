@@ -0,0 +1,576 @@
+use chrono::{DateTime, TimeZone, Utc};
+use futures::future::{BoxFuture, FutureExt};
+use rusoto_core::credential::{
+    AwsCredentials, ContainerProvider, CredentialsError, EnvironmentProvider,
+    InstanceMetadataProvider, ProvideAwsCredentials, StaticProvider,
+};
+use rusoto_core::signature::SignedRequest;
+use rusoto_core::{Client, HttpClient, Region};
+use rusoto_sts::{StsAssumeRoleSessionCredentialsProvider, StsClient};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::Command;
+use std::str::FromStr;
+
+// Profiles can refer to each other through source_profile so stop following them eventually
+const MAX_PROFILE_DEPTH: usize = 5;
+
+type ProfileSections = HashMap<String, HashMap<String, String>>;
+
+#[derive(Debug, PartialEq)]
+enum ProfileSource {
+    Static {
+        key: String,
+        secret: String,
+        token: Option<String>,
+    },
+    Process(String),
+    Sso {
+        start_url: String,
+        sso_region: String,
+        account_id: String,
+        role_name: String,
+    },
+    AssumeRole {
+        role_arn: String,
+        external_id: Option<String>,
+        session_name: Option<String>,
+        source: Box<ProfileSource>,
+    },
+    Environment,
+    InstanceMetadata,
+    Container,
+}
+
+/// Provides credentials for a named profile in the same order as the AWS CLI.
+///
+/// Credentials in environment variables are used first unless the profile was chosen
+/// explicitly. Then the profile is looked up in `~/.aws/config` & `~/.aws/credentials`
+/// (supporting static keys, `role_arn` with `source_profile` or `credential_source`, SSO &
+/// `credential_process`). If the profile is not defined in either file then environment
+/// variables, the container endpoint and finally instance metadata are tried.
+#[derive(Clone)]
+pub struct CredentialsChain {
+    profile: String,
+    region: Region,
+    explicit_profile: bool,
+}
+
+impl CredentialsChain {
+    pub fn new(profile: String, region: Region) -> CredentialsChain {
+        CredentialsChain {
+            profile,
+            region,
+            explicit_profile: false,
+        }
+    }
+
+    /// A chain which always uses `profile` if it is defined, e.g. one passed with `--profile`
+    /// or belonging to another account
+    pub fn with_explicit_profile(profile: String, region: Region) -> CredentialsChain {
+        CredentialsChain {
+            profile,
+            region,
+            explicit_profile: true,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl ProvideAwsCredentials for CredentialsChain {
+    async fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+        if !self.explicit_profile {
+            if let Ok(creds) = EnvironmentProvider::default().credentials().await {
+                return Ok(creds);
+            }
+        }
+
+        let config = read_profile_file("AWS_CONFIG_FILE", ".aws/config");
+        let credentials = read_profile_file("AWS_SHARED_CREDENTIALS_FILE", ".aws/credentials");
+
+        if config.contains_key(&self.profile) || credentials.contains_key(&self.profile) {
+            // The profile exists so any failure is a real problem with it rather than a
+            // reason to silently use some other identity
+            let source = resolve_profile(&self.profile, &config, &credentials, 0)?;
+            return fetch_credentials(&source, &self.region).await;
+        }
+
+        if let Ok(creds) = EnvironmentProvider::default().credentials().await {
+            return Ok(creds);
+        }
+        if let Ok(creds) = ContainerProvider::new().credentials().await {
+            return Ok(creds);
+        }
+        if let Ok(creds) = InstanceMetadataProvider::new().credentials().await {
+            return Ok(creds);
+        }
+
+        Err(CredentialsError::new(format!(
+            "Couldn't find AWS credentials for profile ({}) in the AWS config files, environment, or IAM role",
+            self.profile
+        )))
+    }
+}
+
+fn read_profile_file(env_var: &str, default_path: &str) -> ProfileSections {
+    let path = std::env::var(env_var)
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| {
+            dirs::home_dir()
+                .expect("Could not find home directory")
+                .join(default_path)
+        });
+
+    std::fs::read_to_string(path)
+        .map(|contents| parse_profile_file(&contents))
+        .unwrap_or_default()
+}
+
+fn parse_profile_file(contents: &str) -> ProfileSections {
+    let mut sections = ProfileSections::new();
+    let mut current: Option<String> = None;
+
+    for line in contents.lines().map(|line| line.trim()) {
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+
+        if line.starts_with('[') && line.ends_with(']') {
+            // The config file prefixes profiles with "profile " but the credentials file doesn't
+            let name = line[1..line.len() - 1].trim();
+            let name = name.strip_prefix("profile ").unwrap_or(name).trim();
+            current = Some(name.to_owned());
+            sections.entry(name.to_owned()).or_default();
+            continue;
+        }
+
+        if let (Some(section), Some((key, value))) = (&current, split_key_value(line)) {
+            sections
+                .entry(section.clone())
+                .or_default()
+                .insert(key, value);
+        }
+    }
+
+    sections
+}
+
+fn split_key_value(line: &str) -> Option<(String, String)> {
+    let mut parts = line.splitn(2, '=');
+    let key = parts.next()?.trim();
+    let value = parts.next()?.trim();
+
+    if key.is_empty() || value.is_empty() {
+        return None;
+    }
+
+    Some((key.to_owned(), value.to_owned()))
+}
+
+fn resolve_profile(
+    profile: &str,
+    config: &ProfileSections,
+    credentials: &ProfileSections,
+    depth: usize,
+) -> Result<ProfileSource, CredentialsError> {
+    if depth > MAX_PROFILE_DEPTH {
+        return Err(CredentialsError::new(format!(
+            "Too many nested source profiles while resolving profile ({})",
+            profile
+        )));
+    }
+
+    // Values in the credentials file take precedence over the config file
+    let mut section = config.get(profile).cloned().unwrap_or_default();
+    if let Some(creds) = credentials.get(profile) {
+        section.extend(creds.clone());
+    }
+    let get = |key: &str| section.get(key).cloned();
+
+    if let Some(role_arn) = get("role_arn") {
+        let source = match (get("source_profile"), get("credential_source")) {
+            // A profile can use its own static keys as the source of the assume role
+            (Some(source_profile), _) if source_profile == profile => static_source(&section)
+                .ok_or_else(|| {
+                    CredentialsError::new(format!(
+                        "Profile ({}) is its own source_profile but has no access keys",
+                        profile
+                    ))
+                })?,
+            (Some(source_profile), _) => {
+                resolve_profile(&source_profile, config, credentials, depth + 1)?
+            }
+            (None, Some(credential_source)) => match credential_source.as_str() {
+                "Environment" => ProfileSource::Environment,
+                "Ec2InstanceMetadata" => ProfileSource::InstanceMetadata,
+                "EcsContainer" => ProfileSource::Container,
+                other => {
+                    return Err(CredentialsError::new(format!(
+                        "Unsupported credential_source ({}) in profile ({})",
+                        other, profile
+                    )))
+                }
+            },
+            (None, None) => {
+                return Err(CredentialsError::new(format!(
+                    "Profile ({}) has a role_arn but no source_profile or credential_source",
+                    profile
+                )))
+            }
+        };
+
+        return Ok(ProfileSource::AssumeRole {
+            role_arn,
+            external_id: get("external_id"),
+            session_name: get("role_session_name"),
+            source: Box::new(source),
+        });
+    }
+
+    if let Some(source) = static_source(&section) {
+        return Ok(source);
+    }
+
+    if let Some(source) = sso_source(&section, config) {
+        return source;
+    }
+
+    if let Some(command) = get("credential_process") {
+        return Ok(ProfileSource::Process(command));
+    }
+
+    Err(CredentialsError::new(format!(
+        "Profile ({}) does not contain any credentials",
+        profile
+    )))
+}
+
+fn static_source(section: &HashMap<String, String>) -> Option<ProfileSource> {
+    Some(ProfileSource::Static {
+        key: section.get("aws_access_key_id")?.clone(),
+        secret: section.get("aws_secret_access_key")?.clone(),
+        token: section.get("aws_session_token").cloned(),
+    })
+}
+
+fn sso_source(
+    section: &HashMap<String, String>,
+    config: &ProfileSections,
+) -> Option<Result<ProfileSource, CredentialsError>> {
+    // SSO settings are either on the profile itself or in a shared [sso-session name] section
+    let session = match section.get("sso_session") {
+        Some(name) => config.get(&format!("sso-session {}", name)),
+        None if section.contains_key("sso_start_url") => Some(section),
+        None => return None,
+    };
+
+    let source = (|| {
+        let session = session?;
+        Some(ProfileSource::Sso {
+            start_url: session.get("sso_start_url")?.clone(),
+            sso_region: session.get("sso_region")?.clone(),
+            account_id: section.get("sso_account_id")?.clone(),
+            role_name: section.get("sso_role_name")?.clone(),
+        })
+    })();
+
+    Some(source.ok_or_else(|| CredentialsError::new("SSO profile is missing settings")))
+}
+
+fn fetch_credentials<'a>(
+    source: &'a ProfileSource,
+    region: &'a Region,
+) -> BoxFuture<'a, Result<AwsCredentials, CredentialsError>> {
+    async move {
+        match source {
+            ProfileSource::Static { key, secret, token } => Ok(AwsCredentials::new(
+                key.clone(),
+                secret.clone(),
+                token.clone(),
+                None,
+            )),
+            ProfileSource::Process(command) => run_credential_process(command),
+            ProfileSource::Sso {
+                start_url,
+                sso_region,
+                account_id,
+                role_name,
+            } => fetch_sso_credentials(start_url, sso_region, account_id, role_name).await,
+            ProfileSource::AssumeRole {
+                role_arn,
+                external_id,
+                session_name,
+                source,
+            } => {
+                let source_credentials = fetch_credentials(source, region).await?;
+                let sts_client = StsClient::new_with(
+                    HttpClient::new().expect("Failed to create request dispatcher"),
+                    StaticProvider::from(source_credentials),
+                    region.clone(),
+                );
+
+                StsAssumeRoleSessionCredentialsProvider::new(
+                    sts_client,
+                    role_arn.clone(),
+                    session_name
+                        .clone()
+                        .unwrap_or_else(|| "container-builder".to_owned()),
+                    external_id.clone(),
+                    None,
+                    None,
+                    None,
+                )
+                .credentials()
+                .await
+            }
+            ProfileSource::Environment => EnvironmentProvider::default().credentials().await,
+            ProfileSource::InstanceMetadata => InstanceMetadataProvider::new().credentials().await,
+            ProfileSource::Container => ContainerProvider::new().credentials().await,
+        }
+    }
+    .boxed()
+}
+
+fn run_credential_process(command: &str) -> Result<AwsCredentials, CredentialsError> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .output()
+        .map_err(|err| CredentialsError::new(format!("Credential process failed: {}", err)))?;
+
+    if !output.status.success() {
+        return Err(CredentialsError::new(format!(
+            "Credential process failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        )));
+    }
+
+    Ok(serde_json::from_slice(&output.stdout)?)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SsoCachedToken {
+    start_url: Option<String>,
+    access_token: Option<String>,
+    expires_at: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SsoRoleCredentialsResponse {
+    role_credentials: SsoRoleCredentials,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SsoRoleCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+    expiration: i64,
+}
+
+fn find_sso_token(start_url: &str) -> Result<String, CredentialsError> {
+    let cache_dir = dirs::home_dir()
+        .expect("Could not find home directory")
+        .join(".aws/sso/cache");
+
+    let login_error = || {
+        CredentialsError::new(format!(
+            "No valid SSO session found for {}. Run `aws sso login` first",
+            start_url
+        ))
+    };
+
+    std::fs::read_dir(cache_dir)
+        .map_err(|_| login_error())?
+        .filter_map(|entry| std::fs::read_to_string(entry.ok()?.path()).ok())
+        .filter_map(|contents| serde_json::from_str::<SsoCachedToken>(&contents).ok())
+        .filter(|token| token.start_url.as_deref() == Some(start_url))
+        .filter(|token| {
+            token
+                .expires_at
+                .as_deref()
+                .and_then(|expiry| DateTime::parse_from_rfc3339(expiry).ok())
+                .filter(|expiry| *expiry > Utc::now())
+                .is_some()
+        })
+        .find_map(|token| token.access_token)
+        .ok_or_else(login_error)
+}
+
+async fn fetch_sso_credentials(
+    start_url: &str,
+    sso_region: &str,
+    account_id: &str,
+    role_name: &str,
+) -> Result<AwsCredentials, CredentialsError> {
+    let token = find_sso_token(start_url)?;
+    let region = Region::from_str(sso_region)
+        .map_err(|_| CredentialsError::new(format!("Invalid sso_region ({})", sso_region)))?;
+
+    // The SSO portal uses a bearer token rather than SigV4 so the request isn't signed
+    let mut request = SignedRequest::new("GET", "sso", &region, "/federation/credentials");
+    request.set_hostname(Some(format!("portal.sso.{}.amazonaws.com", region.name())));
+    request.add_param("account_id", account_id);
+    request.add_param("role_name", role_name);
+    request.add_header("x-amz-sso_bearer_token", &token);
+
+    let client =
+        Client::new_not_signing(HttpClient::new().expect("Failed to create request dispatcher"));
+    let response = client
+        .sign_and_dispatch(request)
+        .await
+        .map_err(|err| CredentialsError::new(format!("SSO request failed: {:?}", err)))?
+        .buffer()
+        .await
+        .map_err(|err| CredentialsError::new(format!("SSO request failed: {}", err)))?;
+
+    if !response.status.is_success() {
+        return Err(CredentialsError::new(format!(
+            "SSO request failed with status {}: {}",
+            response.status,
+            response.body_as_str()
+        )));
+    }
+
+    let body: SsoRoleCredentialsResponse = serde_json::from_slice(&response.body)?;
+    let creds = body.role_credentials;
+
+    Ok(AwsCredentials::new(
+        creds.access_key_id,
+        creds.secret_access_key,
+        creds.session_token,
+        Some(Utc.timestamp_millis(creds.expiration)),
+    ))
+}
+
+#[test]
+fn profile_file_parsing_strips_profile_prefix_and_comments() {
+    let sections = parse_profile_file(
+        "# comment
+[default]
+region = us-east-1
+
+[profile dev]
+role_arn = arn:aws:iam::123:role/Dev
+; another comment
+source_profile = default
+",
+    );
+
+    assert_eq!(sections["default"]["region"], "us-east-1");
+    assert_eq!(sections["dev"]["role_arn"], "arn:aws:iam::123:role/Dev");
+    assert_eq!(sections["dev"]["source_profile"], "default");
+    assert_eq!(sections.len(), 2);
+}
+
+#[test]
+fn resolve_profile_uses_static_keys_from_credentials_file() {
+    let config = parse_profile_file("[profile base]\nregion = eu-west-1\n");
+    let credentials =
+        parse_profile_file("[base]\naws_access_key_id = AKIA\naws_secret_access_key = secret\n");
+
+    assert_eq!(
+        resolve_profile("base", &config, &credentials, 0).unwrap(),
+        ProfileSource::Static {
+            key: "AKIA".to_owned(),
+            secret: "secret".to_owned(),
+            token: None,
+        }
+    );
+}
+
+#[test]
+fn resolve_profile_follows_source_profile_for_assume_role() {
+    let config = parse_profile_file(
+        "[profile deploy]
+role_arn = arn:aws:iam::123:role/Deploy
+source_profile = base
+external_id = abc
+",
+    );
+    let credentials =
+        parse_profile_file("[base]\naws_access_key_id = AKIA\naws_secret_access_key = secret\n");
+
+    assert_eq!(
+        resolve_profile("deploy", &config, &credentials, 0).unwrap(),
+        ProfileSource::AssumeRole {
+            role_arn: "arn:aws:iam::123:role/Deploy".to_owned(),
+            external_id: Some("abc".to_owned()),
+            session_name: None,
+            source: Box::new(ProfileSource::Static {
+                key: "AKIA".to_owned(),
+                secret: "secret".to_owned(),
+                token: None,
+            }),
+        }
+    );
+}
+
+#[test]
+fn resolve_profile_supports_credential_source() {
+    let config = parse_profile_file(
+        "[profile ci]\nrole_arn = arn:aws:iam::123:role/Ci\ncredential_source = Ec2InstanceMetadata\n",
+    );
+
+    match resolve_profile("ci", &config, &ProfileSections::new(), 0).unwrap() {
+        ProfileSource::AssumeRole { source, .. } => {
+            assert_eq!(*source, ProfileSource::InstanceMetadata)
+        }
+        other => panic!("Expected assume role but got {:?}", other),
+    }
+}
+
+#[test]
+fn resolve_profile_errors_on_source_profile_cycles() {
+    let config = parse_profile_file(
+        "[profile a]
+role_arn = arn:aws:iam::123:role/A
+source_profile = b
+[profile b]
+role_arn = arn:aws:iam::123:role/B
+source_profile = a
+",
+    );
+
+    assert!(resolve_profile("a", &config, &ProfileSections::new(), 0).is_err());
+}
+
+#[test]
+fn resolve_profile_reads_sso_session_sections() {
+    let config = parse_profile_file(
+        "[profile sso]
+sso_session = corp
+sso_account_id = 123
+sso_role_name = Builder
+
+[sso-session corp]
+sso_start_url = https://corp.awsapps.com/start
+sso_region = eu-west-1
+",
+    );
+
+    assert_eq!(
+        resolve_profile("sso", &config, &ProfileSections::new(), 0).unwrap(),
+        ProfileSource::Sso {
+            start_url: "https://corp.awsapps.com/start".to_owned(),
+            sso_region: "eu-west-1".to_owned(),
+            account_id: "123".to_owned(),
+            role_name: "Builder".to_owned(),
+        }
+    );
+}
+
+#[test]
+fn resolve_profile_falls_back_to_credential_process() {
+    let config = parse_profile_file("[profile proc]\ncredential_process = /usr/bin/creds --json\n");
+
+    assert_eq!(
+        resolve_profile("proc", &config, &ProfileSections::new(), 0).unwrap(),
+        ProfileSource::Process("/usr/bin/creds --json".to_owned())
+    );
+}
@@ -1,52 +1,265 @@
 use regex::Regex;
 use std::io;
-use std::path::PathBuf;
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::str::Chars;
 
+/// Matches files against the rules in a `.dockerignore` file using the same semantics as Docker.
+///
+/// Patterns use Go's `filepath.Match` syntax plus `**` for any number of directories, are
+/// relative to the root of the build context and later patterns override earlier ones, so a
+/// `!` exception rule can re-include files excluded before it.
 pub struct DockerIgnore {
-    rules: Vec<String>,
+    patterns: Vec<Pattern>,
+}
+
+#[derive(Debug)]
+pub enum DockerIgnoreError {
+    FileReadFailed(io::Error),
+    InvalidPattern(String),
+}
+
+struct Pattern {
+    exclusion: bool,
+    matcher: Regex,
 }
 
 impl DockerIgnore {
-    pub fn new(path: PathBuf) -> io::Result<DockerIgnore> {
-        let contents = std::fs::read_to_string(path)?;
+    pub fn new(path: PathBuf) -> Result<DockerIgnore, DockerIgnoreError> {
+        let contents = std::fs::read_to_string(path).map_err(DockerIgnoreError::FileReadFailed)?;
 
-        let lines = contents.split("\n").collect();
+        Self::from_rules(&Self::read_rules(&contents))
+    }
 
-        Ok(DockerIgnore {
-            rules: Self::remove_comments(lines)
-                .iter_mut()
-                .map(|s| s.to_owned())
-                .collect(),
-        })
+    pub fn from_rules(rules: &[String]) -> Result<DockerIgnore, DockerIgnoreError> {
+        let patterns = rules
+            .iter()
+            .map(|rule| Pattern::new(rule))
+            .collect::<Result<Vec<Pattern>, DockerIgnoreError>>()?;
+
+        Ok(DockerIgnore { patterns })
+    }
+
+    fn read_rules(contents: &str) -> Vec<String> {
+        let contents = contents.trim_start_matches('\u{feff}');
+
+        Self::remove_comments(contents.split('\n').collect())
+            .into_iter()
+            .map(|line| {
+                let line = line.trim();
+                let (invert, pattern) = match line.strip_prefix('!') {
+                    Some(pattern) => (true, pattern.trim()),
+                    None => (false, line),
+                };
+
+                let mut pattern = if pattern.is_empty() {
+                    String::new()
+                } else {
+                    clean_path(pattern)
+                };
+                if pattern.len() > 1 && pattern.starts_with('/') {
+                    pattern.remove(0);
+                }
+
+                if invert {
+                    format!("!{}", pattern)
+                } else {
+                    pattern
+                }
+            })
+            .collect()
     }
 
     fn remove_comments(lines: Vec<&str>) -> Vec<&str> {
         lines
             .into_iter()
-            .filter(|line| line.chars().nth(0) != Some('#'))
+            .filter(|line| !line.starts_with('#'))
             .filter(|line| !line.trim().is_empty())
             .collect()
     }
 
-    pub fn filter_files(&self, files: &Vec<PathBuf>) -> Vec<PathBuf> {
-        files
-            .iter()
-            .filter(|file| !self.should_remove_file(file))
-            .map(|path| path.clone())
-            .collect::<Vec<PathBuf>>()
-            .clone()
+    /// Add exception rules for files that Docker always sends with the build context,
+    /// such as the Dockerfile and the `.dockerignore` file itself
+    pub fn keep_files(mut self, files: &[&str]) -> DockerIgnore {
+        for file in files {
+            if self.matches(file) {
+                self.patterns.push(
+                    Pattern::new(&format!("!{}", escape_pattern(file)))
+                        .expect("Escaped file names are valid patterns"),
+                );
+            }
+        }
+        self
+    }
+
+    /// Check whether a path relative to the context root should be excluded
+    pub fn matches(&self, relative_path: &str) -> bool {
+        let file = clean_path(relative_path);
+        let parent_dirs: Vec<&str> = match file.rfind('/') {
+            Some(index) => file[..index].split('/').collect(),
+            None => vec![],
+        };
+
+        let mut matched = false;
+        for pattern in self.patterns.iter() {
+            // Only inclusions can change an unmatched file and only exclusions a matched one
+            if pattern.exclusion != matched {
+                continue;
+            }
+
+            let mut is_match = pattern.matcher.is_match(&file);
+
+            // A pattern which matches a parent directory matches everything inside it
+            if !is_match {
+                is_match = (1..=parent_dirs.len())
+                    .any(|depth| pattern.matcher.is_match(&parent_dirs[..depth].join("/")));
+            }
+
+            if is_match {
+                matched = !pattern.exclusion;
+            }
+        }
+
+        matched
     }
 
-    fn should_remove_file(&self, file: &PathBuf) -> bool {
-        self.rules
+    pub fn filter_files(&self, context_root: &Path, files: &[PathBuf]) -> Vec<PathBuf> {
+        files
             .iter()
-            .map(|rule| {
-                let s = file.to_str().unwrap();
-                let matcher = Regex::new(rule).unwrap();
-                matcher.is_match(s)
+            .filter(|file| {
+                let relative = file.strip_prefix(context_root).unwrap_or(file);
+                !self.matches(&relative.to_string_lossy())
             })
-            .any(|result| result)
+            .cloned()
+            .collect()
+    }
+}
+
+impl Pattern {
+    fn new(rule: &str) -> Result<Pattern, DockerIgnoreError> {
+        let (exclusion, pattern) = match rule.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, rule),
+        };
+
+        if exclusion && pattern.is_empty() {
+            return Err(DockerIgnoreError::InvalidPattern(format!(
+                "Illegal exclusion pattern: \"{}\"",
+                rule
+            )));
+        }
+
+        let pattern = clean_path(pattern);
+        let matcher = Regex::new(&pattern_to_regex(&pattern)).map_err(|_| {
+            DockerIgnoreError::InvalidPattern(format!("Syntax error in pattern: \"{}\"", rule))
+        })?;
+
+        Ok(Pattern { exclusion, matcher })
+    }
+}
+
+fn pattern_to_regex(pattern: &str) -> String {
+    let mut regex = "^".to_owned();
+    let mut chars = pattern.chars().peekable();
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                // Treat **/ as **
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                }
+                if chars.peek().is_none() {
+                    regex.push_str(".*");
+                } else {
+                    regex.push_str("(.*/)?");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => push_class(&mut regex, &mut chars),
+            // As in Go a trailing backslash matches itself
+            '\\' => push_literal(&mut regex, chars.next().unwrap_or('\\')),
+            _ => push_literal(&mut regex, ch),
+        }
+    }
+
+    regex.push('$');
+    regex
+}
+
+/// Copy a `[...]` class from a pattern, where every character is literal except for a
+/// leading `^`, ranges & backslash escapes. An unclosed class is left unclosed so the regex
+/// fails to compile.
+fn push_class(regex: &mut String, chars: &mut Peekable<Chars<'_>>) {
+    regex.push('[');
+    if chars.peek() == Some(&'^') {
+        chars.next();
+        regex.push('^');
+    }
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            ']' => {
+                regex.push(']');
+                return;
+            }
+            '-' => regex.push('-'),
+            '\\' => {
+                if let Some(escaped) = chars.next() {
+                    push_literal(regex, escaped);
+                }
+            }
+            _ => push_literal(regex, ch),
+        }
+    }
+}
+
+fn push_literal(regex: &mut String, ch: char) {
+    regex.push_str(&regex::escape(&ch.to_string()));
+}
+
+fn escape_pattern(file: &str) -> String {
+    file.chars()
+        .flat_map(|ch| match ch {
+            '*' | '?' | '[' | ']' | '\\' => vec!['\\', ch],
+            _ => vec![ch],
+        })
+        .collect()
+}
+
+/// Lexically clean a slash separated path in the same way as Go's `filepath.Clean`
+fn clean_path(path: &str) -> String {
+    let rooted = path.starts_with('/');
+    let mut parts: Vec<&str> = vec![];
+
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                if matches!(parts.last(), Some(last) if *last != "..") {
+                    parts.pop();
+                } else if !rooted {
+                    parts.push("..");
+                }
+            }
+            _ => parts.push(part),
+        }
     }
+
+    let joined = parts.join("/");
+    match (rooted, joined.is_empty()) {
+        (true, _) => format!("/{}", joined),
+        (false, true) => ".".to_owned(),
+        (false, false) => joined,
+    }
+}
+
+#[cfg(test)]
+fn ignore_from(rules: &[&str]) -> DockerIgnore {
+    let rules: Vec<String> = rules.iter().map(|rule| rule.to_string()).collect();
+    DockerIgnore::from_rules(&rules).unwrap()
 }
 
 #[test]
@@ -56,6 +269,33 @@ fn remove_lines_works() {
     assert_eq!(DockerIgnore::remove_comments(lines), vec!["second_line"]);
 }
 
+#[test]
+fn read_rules_cleans_and_anchors_patterns() {
+    let contents = "test1
+/test2
+/a/file/here
+
+lastfile
+# this is a comment
+! /inverted/abs/path
+!
+!
+";
+
+    assert_eq!(
+        DockerIgnore::read_rules(contents),
+        vec![
+            "test1",
+            "test2",
+            "a/file/here",
+            "lastfile",
+            "!inverted/abs/path",
+            "!",
+            "!"
+        ]
+    );
+}
+
 #[test]
 fn filter_works_correctly() {
     use std::str::FromStr;
@@ -71,11 +311,9 @@ fn filter_works_correctly() {
         .map(|file| PathBuf::from_str(file).unwrap())
         .collect();
 
-    let ignore = DockerIgnore {
-        rules: vec!["dependencies".to_owned()],
-    };
+    let ignore = ignore_from(&["dependencies"]);
 
-    let filtered = ignore.filter_files(&paths);
+    let filtered = ignore.filter_files(Path::new("example_proj"), &paths);
 
     let expected: Vec<PathBuf> = vec!["example_proj/src/code.sh", "example_proj/Dockerfile"]
         .into_iter()
@@ -84,3 +322,208 @@ fn filter_works_correctly() {
 
     assert_eq!(filtered, expected);
 }
+
+#[test]
+fn patterns_are_not_matched_anywhere_in_the_path() {
+    let ignore = ignore_from(&["node_modules"]);
+
+    assert!(ignore.matches("node_modules"));
+    assert!(ignore.matches("node_modules/left-pad/index.js"));
+    assert!(!ignore.matches("src/my_node_modules.rs"));
+    assert!(!ignore.matches("src/node_modules/index.js"));
+}
+
+#[test]
+fn glob_patterns_do_not_panic() {
+    let ignore = ignore_from(&["*.log"]);
+
+    assert!(ignore.matches("build.log"));
+    assert!(!ignore.matches("logs/build.log"));
+}
+
+#[test]
+fn matches_mirrors_docker_pattern_tests() {
+    let cases = vec![
+        ("**", "file", true),
+        ("**", "file/", true),
+        ("**/", "file", true),
+        ("**/", "file/", true),
+        ("**", "/", true),
+        ("**/", "/", true),
+        ("**", "dir/file", true),
+        ("**/", "dir/file", true),
+        ("**", "dir/file/", true),
+        ("**/", "dir/file/", true),
+        ("**/**", "dir/file", true),
+        ("**/**", "dir/file/", true),
+        ("dir/**", "dir/file", true),
+        ("dir/**", "dir/file/", true),
+        ("dir/**", "dir/dir2/file", true),
+        ("dir/**", "dir/dir2/file/", true),
+        ("**/dir", "dir", true),
+        ("**/dir", "dir/file", true),
+        ("**/dir2/*", "dir/dir2/file", true),
+        ("**/dir2/*", "dir/dir2/file/", true),
+        ("**/dir2/**", "dir/dir2/dir3/file", true),
+        ("**/dir2/**", "dir/dir2/dir3/file/", true),
+        ("**file", "file", true),
+        ("**file", "dir/file", true),
+        ("**/file", "dir/file", true),
+        ("**file", "dir/dir/file", true),
+        ("**/file", "dir/dir/file", true),
+        ("**/file*", "dir/dir/file", true),
+        ("**/file*", "dir/dir/file.txt", true),
+        ("**/file*txt", "dir/dir/file.txt", true),
+        ("**/file*.txt", "dir/dir/file.txt", true),
+        ("**/file*.txt*", "dir/dir/file.txt", true),
+        ("**/**/*.txt", "dir/dir/file.txt", true),
+        ("**/**/*.txt2", "dir/dir/file.txt", false),
+        ("**/*.txt", "file.txt", true),
+        ("**/**/*.txt", "file.txt", true),
+        ("a**/*.txt", "a/file.txt", true),
+        ("a**/*.txt", "a/dir/file.txt", true),
+        ("a**/*.txt", "a/dir/dir/file.txt", true),
+        ("a/*.txt", "a/dir/file.txt", false),
+        ("a/*.txt", "a/file.txt", true),
+        ("a/*.txt**", "a/file.txt", true),
+        ("a[b-d]e", "ae", false),
+        ("a[b-d]e", "ace", true),
+        ("a[b-d]e", "aae", false),
+        ("a[^b-d]e", "aze", true),
+        (".*", ".foo", true),
+        (".*", "foo", false),
+        ("abc.def", "abcdef", false),
+        ("abc.def", "abc.def", true),
+        ("abc.def", "abcZdef", false),
+        ("abc?def", "abcZdef", true),
+        ("abc?def", "abcdef", false),
+        ("a\\\\", "a\\", true),
+        ("**/foo/bar", "foo/bar", true),
+        ("**/foo/bar", "dir/foo/bar", true),
+        ("**/foo/bar", "dir/dir2/foo/bar", true),
+        ("abc/**", "abc", false),
+        ("abc/**", "abc/def", true),
+        ("abc/**", "abc/def/ghi", true),
+        ("**/.foo", ".foo", true),
+        ("**/.foo", "bar.foo", false),
+        ("a(b)c/def", "a(b)c/def", true),
+        ("a(b)c/def", "a(b)c/xyz", false),
+        ("a.|)$(}+{bc", "a.|)$(}+{bc", true),
+        (
+            "dist/proxy.py-2.4.0rc3.dev36+g08acad9-py3-none-any.whl",
+            "dist/proxy.py-2.4.0rc3.dev36+g08acad9-py3-none-any.whl",
+            true,
+        ),
+        (
+            "dist/*.whl",
+            "dist/proxy.py-2.4.0rc3.dev36+g08acad9-py3-none-any.whl",
+            true,
+        ),
+    ];
+
+    for (pattern, file, expected) in cases {
+        assert_eq!(
+            ignore_from(&[pattern]).matches(file),
+            expected,
+            "pattern ({}) against file ({})",
+            pattern,
+            file
+        );
+    }
+}
+
+#[test]
+fn wildcard_matches_everything() {
+    assert!(ignore_from(&["*"]).matches("fileutils.go"));
+}
+
+#[test]
+fn no_patterns_match_nothing() {
+    assert!(!ignore_from(&[]).matches("/any/path/there"));
+}
+
+#[test]
+fn exclusion_after_pattern_wins() {
+    assert!(!ignore_from(&["fileutils.go", "!fileutils.go"]).matches("fileutils.go"));
+}
+
+#[test]
+fn pattern_after_exclusion_wins() {
+    assert!(ignore_from(&["!fileutils.go", "fileutils.go"]).matches("fileutils.go"));
+}
+
+#[test]
+fn exclusions_can_reinclude_files_in_excluded_folders() {
+    assert!(!ignore_from(&["docs", "!docs/README.md"]).matches("docs/README.md"));
+    assert!(!ignore_from(&["docs/", "!docs/README.md"]).matches("docs/README.md"));
+    assert!(!ignore_from(&["docs/*", "!docs/README.md"]).matches("docs/README.md"));
+    assert!(ignore_from(&["docs", "!docs/README.md"]).matches("docs/other.md"));
+}
+
+#[test]
+fn pattern_does_not_match_unrelated_directory() {
+    assert!(!ignore_from(&["*.go"]).matches(".git"));
+}
+
+#[test]
+fn single_exclamation_is_an_error() {
+    assert!(DockerIgnore::from_rules(&["!".to_owned()]).is_err());
+}
+
+#[test]
+fn malformed_patterns_are_an_error() {
+    assert!(DockerIgnore::from_rules(&["[".to_owned()]).is_err());
+    assert!(DockerIgnore::from_rules(&["[a-".to_owned()]).is_err());
+    assert!(DockerIgnore::from_rules(&["[]".to_owned()]).is_err());
+}
+
+#[test]
+fn patterns_match_like_go_filepath_match() {
+    let cases = vec![
+        ("a^b", "a^b", true),
+        ("a$b", "a$b", true),
+        ("\\d", "d", true),
+        ("\\d", "1", false),
+        ("\\w*", "w.txt", true),
+        ("\\w*", "a.txt", false),
+        ("[*?]", "*", true),
+        ("[*?]", "?", true),
+        ("[*?]", "a", false),
+        ("[*?]x", "abx", false),
+        ("[a-c]x", "bx", true),
+        ("[a-c]x", "dx", false),
+        ("[^a-c]x", "dx", true),
+        ("[\\]]", "]", true),
+        ("[.]", "a", false),
+        ("[&&b]", "&", true),
+    ];
+
+    for (pattern, file, expected) in cases {
+        assert_eq!(
+            ignore_from(&[pattern]).matches(file),
+            expected,
+            "pattern ({}) against file ({})",
+            pattern,
+            file
+        );
+    }
+}
+
+#[test]
+fn keep_files_reincludes_build_files() {
+    let ignore = ignore_from(&["*", "!src"]).keep_files(&["Dockerfile", ".dockerignore"]);
+
+    assert!(!ignore.matches("Dockerfile"));
+    assert!(!ignore.matches(".dockerignore"));
+    assert!(!ignore.matches("src/code.sh"));
+    assert!(ignore.matches("README.md"));
+}
+
+#[test]
+fn clean_path_matches_go_semantics() {
+    assert_eq!(clean_path("a//b/./c/"), "a/b/c");
+    assert_eq!(clean_path("a/../../b"), "../b");
+    assert_eq!(clean_path("/../a"), "/a");
+    assert_eq!(clean_path(""), ".");
+    assert_eq!(clean_path("/"), "/");
+}
@@ -1,28 +1,32 @@
-use rusoto_core::{credential::ProfileProvider, HttpClient, Region};
+use crate::{Architecture, AwsClientFactory, Backoff};
 use rusoto_ec2::{
-    CreateKeyPairRequest, DeleteKeyPairRequest, DescribeImagesRequest, DescribeInstancesRequest,
-    Ec2, Ec2Client, Filter, Image, Instance, StartInstancesRequest, StopInstancesRequest,
+    AuthorizeSecurityGroupIngressRequest, CreateKeyPairRequest, DeleteKeyPairRequest,
+    DescribeImagesRequest, DescribeInstanceTypesRequest, DescribeInstancesRequest,
+    DescribeSecurityGroupsRequest, Ec2, Ec2Client, Filter, GetConsoleOutputRequest, Image,
+    Instance, IpPermission, IpRange, RevokeSecurityGroupIngressRequest, StartInstancesRequest,
+    StopInstancesRequest,
 };
+use std::{thread::sleep, time::Duration};
+
+#[derive(Debug)]
+pub enum InstanceWaitError {
+    DescribeInstanceFailed,
+    TimedOut { last_state: String },
+}
+
+pub struct InstanceDetails {
+    pub state: String,
+    pub instance_type: String,
+    pub public_ip: Option<String>,
+}
 
 pub struct EC2Client {
     client: Ec2Client,
 }
 
 impl EC2Client {
-    pub fn new(profile: String) -> EC2Client {
-        let home_dir = dirs::home_dir().expect("Could not find home directory");
-
-        // Create EC2 client
-        let profile_provider =
-            ProfileProvider::with_configuration(home_dir.join(".aws/credentials"), profile);
-
-        let client = Ec2Client::new_with(
-            HttpClient::new().expect("Failed to create request dispatcher"),
-            profile_provider.clone(),
-            Region::UsEast1,
-        );
-
-        EC2Client { client }
+    pub fn new(aws: &AwsClientFactory) -> EC2Client {
+        EC2Client { client: aws.ec2() }
     }
 
     pub async fn start_instance(&self, instance_id: String) -> bool {
@@ -52,6 +56,66 @@ impl EC2Client {
     }
 
     pub async fn get_instance_ip(&self, instance_id: String) -> Option<String> {
+        self.describe_instance(instance_id).await?.public_ip_address
+    }
+
+    pub async fn get_instance_state(&self, instance_id: String) -> Option<String> {
+        self.describe_instance(instance_id).await?.state?.name
+    }
+
+    pub async fn get_instance_details(&self, instance_id: String) -> Option<InstanceDetails> {
+        let instance = self.describe_instance(instance_id).await?;
+
+        Some(InstanceDetails {
+            state: instance.state?.name?,
+            instance_type: instance.instance_type?,
+            public_ip: instance.public_ip_address,
+        })
+    }
+
+    /// The system log of the instance, which is only available a few minutes after it boots
+    pub async fn get_console_output(&self, instance_id: String) -> Option<String> {
+        let output = self
+            .client
+            .get_console_output(GetConsoleOutputRequest {
+                instance_id,
+                ..Default::default()
+            })
+            .await
+            .ok()?
+            .output?;
+
+        let output = base64::decode(output.trim()).ok()?;
+        Some(String::from_utf8_lossy(&output).into_owned())
+    }
+
+    /// Poll the instance until it reaches `target_state` (e.g. "running" or "stopped")
+    pub async fn wait_for_instance_state(
+        &self,
+        instance_id: String,
+        target_state: &str,
+        timeout: Duration,
+    ) -> Result<(), InstanceWaitError> {
+        let mut backoff = Backoff::new(Duration::from_secs(2), Duration::from_secs(15), timeout);
+
+        loop {
+            let state = self
+                .get_instance_state(instance_id.clone())
+                .await
+                .ok_or(InstanceWaitError::DescribeInstanceFailed)?;
+
+            if state == target_state {
+                return Ok(());
+            }
+
+            match backoff.next_delay() {
+                Some(delay) => sleep(delay),
+                None => return Err(InstanceWaitError::TimedOut { last_state: state }),
+            }
+        }
+    }
+
+    async fn describe_instance(&self, instance_id: String) -> Option<Instance> {
         let result = self
             .client
             .describe_instances(DescribeInstancesRequest {
@@ -62,18 +126,12 @@ impl EC2Client {
             .await
             .ok()?;
 
-        let instances: Vec<Instance> = result
-            .reservations
-            .unwrap()
+        result
+            .reservations?
             .into_iter()
             .flat_map(|res| res.instances)
             .flatten()
-            .collect();
-
-        instances
-            .first()
-            .map(|instance| instance.public_ip_address.clone())
-            .flatten()
+            .next()
     }
 
     pub async fn delete_key_pair(&self, key_pair_name: String) -> bool {
@@ -102,14 +160,105 @@ impl EC2Client {
         result.ok().map(|key| key.key_material).flatten()
     }
 
-    pub async fn get_amazon_linux_2_ami(&self) -> Option<Vec<Image>> {
+    /// The ID of the first security group attached to the instance
+    pub async fn get_instance_security_group(&self, instance_id: String) -> Option<String> {
+        self.describe_instance(instance_id)
+            .await?
+            .security_groups?
+            .into_iter()
+            .next()?
+            .group_id
+    }
+
+    /// The CIDR ranges currently allowed to reach port 22 in the security group
+    pub async fn get_ssh_ingress(&self, group_id: String) -> Option<Vec<String>> {
+        let result = self
+            .client
+            .describe_security_groups(DescribeSecurityGroupsRequest {
+                dry_run: Some(false),
+                group_ids: Some(vec![group_id]),
+                ..DescribeSecurityGroupsRequest::default()
+            })
+            .await
+            .ok()?;
+
+        let group = result.security_groups?.into_iter().next()?;
+
+        Some(
+            group
+                .ip_permissions
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|permission| permission.from_port == Some(22))
+                .flat_map(|permission| permission.ip_ranges.unwrap_or_default())
+                .filter_map(|range| range.cidr_ip)
+                .collect(),
+        )
+    }
+
+    pub async fn authorize_ssh_ingress(&self, group_id: String, cidrs: &[String]) -> bool {
+        let result = self
+            .client
+            .authorize_security_group_ingress(AuthorizeSecurityGroupIngressRequest {
+                dry_run: Some(false),
+                group_id: Some(group_id),
+                ip_permissions: Some(vec![create_ssh_permission(cidrs)]),
+                ..AuthorizeSecurityGroupIngressRequest::default()
+            })
+            .await;
+
+        result.is_ok()
+    }
+
+    pub async fn revoke_ssh_ingress(&self, group_id: String, cidrs: &[String]) -> bool {
+        let result = self
+            .client
+            .revoke_security_group_ingress(RevokeSecurityGroupIngressRequest {
+                dry_run: Some(false),
+                group_id: Some(group_id),
+                ip_permissions: Some(vec![create_ssh_permission(cidrs)]),
+                ..RevokeSecurityGroupIngressRequest::default()
+            })
+            .await;
+
+        result.is_ok()
+    }
+
+    /// The architectures an instance type can run, e.g. ["arm64"] for t4g.micro
+    pub async fn get_instance_type_architectures(
+        &self,
+        instance_type: String,
+    ) -> Option<Vec<String>> {
+        let result = self
+            .client
+            .describe_instance_types(DescribeInstanceTypesRequest {
+                dry_run: Some(false),
+                instance_types: Some(vec![instance_type]),
+                ..DescribeInstanceTypesRequest::default()
+            })
+            .await
+            .ok()?;
+
+        result
+            .instance_types?
+            .into_iter()
+            .next()?
+            .processor_info?
+            .supported_architectures
+    }
+
+    pub async fn get_amazon_linux_2_ami(&self, architecture: Architecture) -> Option<Vec<Image>> {
         // Find the correct AWS Amazon Linux 2 AMI
         let images_request = self
             .client
             .describe_images(DescribeImagesRequest {
                 dry_run: Some(false),
                 filters: Some(vec![
-                    create_filter("name", "amzn2-ami-hvm-2.0.????????.?-x86_64-gp2"),
+                    create_filter(
+                        "name",
+                        &format!("amzn2-ami-hvm-2.0.????????.?-{}-gp2", architecture.name()),
+                    ),
+                    create_filter("architecture", architecture.name()),
                     create_filter("state", "available"),
                 ]),
                 owners: Some(vec![String::from("amazon")]),
@@ -124,6 +273,24 @@ impl EC2Client {
     }
 }
 
+fn create_ssh_permission(cidrs: &[String]) -> IpPermission {
+    IpPermission {
+        from_port: Some(22),
+        to_port: Some(22),
+        ip_protocol: Some("tcp".to_owned()),
+        ip_ranges: Some(
+            cidrs
+                .iter()
+                .map(|cidr| IpRange {
+                    cidr_ip: Some(cidr.clone()),
+                    description: Some("container-builder SSH".to_owned()),
+                })
+                .collect(),
+        ),
+        ..IpPermission::default()
+    }
+}
+
 fn create_filter(name: &str, value: &str) -> Filter {
     Filter {
         name: Some(name.to_owned()),
@@ -0,0 +1,139 @@
+use std::path::Path;
+use std::process::Command;
+
+// Docker tags are at most 128 characters
+const MAX_TAG_LENGTH: usize = 128;
+const DIRTY_SUFFIX: &str = "-dirty";
+
+#[derive(Debug)]
+pub enum GitTagError {
+    GitNotFound(String),
+    NotARepository(String),
+    CommandFailed(String),
+}
+
+/// Derive image tags from the state of the git repository containing `path`: the short SHA
+/// of HEAD, the branch name and the git tag pointing at HEAD if there is one. Every tag gets
+/// a `-dirty` suffix when the working tree has uncommitted changes.
+pub fn git_tags(path: &Path) -> Result<Vec<String>, GitTagError> {
+    let sha = git(path, &["rev-parse", "--short", "HEAD"])?
+        .ok_or_else(|| GitTagError::NotARepository(path.display().to_string()))?;
+
+    // Detached HEADs have no branch & untagged commits no exact tag
+    let branch = git(path, &["symbolic-ref", "--short", "-q", "HEAD"])?;
+    let exact_tag = git(path, &["describe", "--tags", "--exact-match", "HEAD"])?;
+
+    let status = git(path, &["status", "--porcelain"])?.unwrap_or_default();
+    let dirty = !status.is_empty();
+
+    let mut tags: Vec<String> = vec![];
+    for name in vec![Some(sha), branch, exact_tag].into_iter().flatten() {
+        let tag = docker_tag(&name, dirty);
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+
+    Ok(tags)
+}
+
+/// Run git in `path`, returning its trimmed output or `None` if it exited unsuccessfully
+fn git(path: &Path, args: &[&str]) -> Result<Option<String>, GitTagError> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(path)
+        .args(args)
+        .output()
+        .map_err(|err| GitTagError::GitNotFound(err.to_string()))?;
+
+    if !output.status.success() {
+        return Ok(None);
+    }
+
+    String::from_utf8(output.stdout)
+        .map(|stdout| Some(stdout.trim().to_owned()))
+        .map_err(|err| GitTagError::CommandFailed(err.to_string()))
+}
+
+/// Make `name` a valid Docker tag: `[A-Za-z0-9_][A-Za-z0-9_.-]{0,127}`
+fn docker_tag(name: &str, dirty: bool) -> String {
+    let mut tag: String = name
+        .chars()
+        .map(|c| match c {
+            'A'..='Z' | 'a'..='z' | '0'..='9' | '_' | '.' | '-' => c,
+            _ => '-',
+        })
+        .collect();
+
+    if tag.starts_with('.') || tag.starts_with('-') {
+        tag.replace_range(..1, "_");
+    }
+
+    let max_length = if dirty {
+        MAX_TAG_LENGTH - DIRTY_SUFFIX.len()
+    } else {
+        MAX_TAG_LENGTH
+    };
+    tag.truncate(max_length);
+
+    if dirty {
+        tag.push_str(DIRTY_SUFFIX);
+    }
+    tag
+}
+
+#[test]
+fn branch_names_are_sanitised_for_docker() {
+    assert_eq!(docker_tag("feature/new-login", false), "feature-new-login");
+    assert_eq!(docker_tag("-fix#12", false), "_fix-12");
+    assert_eq!(docker_tag("v1.2.0", true), "v1.2.0-dirty");
+
+    let long_name = "a".repeat(200);
+    assert_eq!(docker_tag(&long_name, false).len(), 128);
+    assert!(docker_tag(&long_name, true).ends_with("a-dirty"));
+    assert_eq!(docker_tag(&long_name, true).len(), 128);
+}
+
+#[test]
+fn tags_follow_the_repository_state() {
+    let repo = tempfile::tempdir().unwrap();
+    let run = |args: &[&str]| {
+        let status = Command::new("git")
+            .arg("-C")
+            .arg(repo.path())
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .output()
+            .unwrap()
+            .status;
+        assert!(status.success(), "git {:?} failed", args);
+    };
+
+    run(&["init", "-q"]);
+    run(&["checkout", "-q", "-b", "feature/login"]);
+    std::fs::write(repo.path().join("Dockerfile"), "FROM scratch\n").unwrap();
+    run(&["add", "Dockerfile"]);
+    run(&["commit", "-q", "-m", "Initial commit"]);
+    run(&["tag", "v1.0.0"]);
+
+    let sha = git(repo.path(), &["rev-parse", "--short", "HEAD"])
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        git_tags(repo.path()).unwrap(),
+        vec![sha.clone(), "feature-login".to_owned(), "v1.0.0".to_owned()]
+    );
+
+    std::fs::write(repo.path().join("Dockerfile"), "FROM alpine\n").unwrap();
+    assert_eq!(
+        git_tags(repo.path()).unwrap(),
+        vec![
+            format!("{}-dirty", sha),
+            "feature-login-dirty".to_owned(),
+            "v1.0.0-dirty".to_owned()
+        ]
+    );
+
+    let not_a_repo = tempfile::tempdir().unwrap();
+    assert!(git_tags(not_a_repo.path()).is_err());
+}
@@ -0,0 +1,179 @@
+use ssh2::{CheckResult, KnownHostFileKind, KnownHosts, Session};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub enum HostKeyError {
+    Mismatch(String),
+    StoreFailed(String),
+}
+
+impl From<ssh2::Error> for HostKeyError {
+    fn from(error: ssh2::Error) -> Self {
+        HostKeyError::StoreFailed(error.to_string())
+    }
+}
+
+impl From<std::io::Error> for HostKeyError {
+    fn from(error: std::io::Error) -> Self {
+        HostKeyError::StoreFailed(error.to_string())
+    }
+}
+
+/// The pinned SSH host keys of the builder instance. They are kept in OpenSSH known_hosts
+/// format so `ssh -o UserKnownHostsFile=...` can check them too.
+pub struct HostKeys {
+    path: PathBuf,
+}
+
+impl HostKeys {
+    pub fn new(path: PathBuf) -> HostKeys {
+        HostKeys { path }
+    }
+
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Check the key `host` presented during the handshake. A host without pinned keys is
+    /// trusted the first time it's seen & its key pinned.
+    pub fn verify(&self, session: &Session, host: &str) -> Result<(), HostKeyError> {
+        let (key, key_type) = session
+            .host_key()
+            .ok_or_else(|| HostKeyError::StoreFailed("No host key was presented".to_owned()))?;
+
+        let mut known_hosts = self.load(session)?;
+        match known_hosts.check(host, key) {
+            CheckResult::Match => Ok(()),
+            CheckResult::NotFound => {
+                known_hosts.add(host, key, "container-builder", key_type.into())?;
+                known_hosts.write_file(&self.path, KnownHostFileKind::OpenSSH)?;
+                Ok(())
+            }
+            CheckResult::Mismatch => Err(HostKeyError::Mismatch(format!(
+                "The host key of {} does not match the one pinned in {}. If the instance was \
+replaced remove the file and connect again, otherwise something may be intercepting the connection",
+                host,
+                self.path.display()
+            ))),
+            CheckResult::Failure => Err(HostKeyError::StoreFailed(format!(
+                "Failed to check the host key of {}",
+                host
+            ))),
+        }
+    }
+
+    /// Replace all pinned keys with `keys` for `host`. Each key is `<type> <base64>`.
+    pub fn pin(&self, host: &str, keys: &[String]) -> Result<(), HostKeyError> {
+        let lines: Vec<String> = keys
+            .iter()
+            .map(|key| format!("{} {}\n", host, key))
+            .collect();
+        fs::write(&self.path, lines.concat())?;
+        Ok(())
+    }
+
+    /// Move the keys pinned for `old_host` to `new_host` after the instance's IP changes
+    pub fn repin(&self, old_host: &str, new_host: &str) -> Result<(), HostKeyError> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+
+        // Without pinned keys the new host is trusted on first use
+        if let Some(contents) = repin_lines(&contents, old_host, new_host) {
+            fs::write(&self.path, contents)?;
+        }
+        Ok(())
+    }
+
+    /// Forget the keys when the instance is replaced
+    pub fn forget(&self) -> Result<(), HostKeyError> {
+        match fs::remove_file(&self.path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn load(&self, session: &Session) -> Result<KnownHosts, HostKeyError> {
+        let mut known_hosts = session.known_hosts()?;
+        if self.path.exists() {
+            known_hosts.read_file(&self.path, KnownHostFileKind::OpenSSH)?;
+        }
+        Ok(known_hosts)
+    }
+}
+
+fn repin_lines(contents: &str, old_host: &str, new_host: &str) -> Option<String> {
+    let moved: Vec<String> = contents
+        .lines()
+        .filter_map(|line| {
+            let (host, key) = line.split_once(' ')?;
+            if host == old_host {
+                Some(format!("{} {}\n", new_host, key))
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    if moved.is_empty() {
+        None
+    } else {
+        Some(moved.concat())
+    }
+}
+
+/// Find the host keys cloud-init prints to the console of a new instance
+pub fn parse_console_host_keys(console_output: &str) -> Vec<String> {
+    console_output
+        .lines()
+        .skip_while(|line| !line.contains("-----BEGIN SSH HOST KEY KEYS-----"))
+        .skip(1)
+        .take_while(|line| !line.contains("-----END SSH HOST KEY KEYS-----"))
+        .filter_map(|line| {
+            // Lines may be prefixed, e.g. with "ec2: "
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let index = parts
+                .iter()
+                .position(|part| part.starts_with("ssh-") || part.starts_with("ecdsa-"))?;
+            let key = parts.get(index + 1)?;
+            Some(format!("{} {}", parts[index], key))
+        })
+        .collect()
+}
+
+#[test]
+fn console_host_keys_are_read_from_the_cloud_init_block() {
+    let output = "\
+ec2: -----BEGIN SSH HOST KEY FINGERPRINTS-----
+ec2: 256 SHA256:abc no comment (ECDSA)
+ec2: -----END SSH HOST KEY FINGERPRINTS-----
+-----BEGIN SSH HOST KEY KEYS-----
+ecdsa-sha2-nistp256 AAAAE2VjZHNh
+ssh-ed25519 AAAAC3NzaC1lZDI1NTE5
+-----END SSH HOST KEY KEYS-----
+ssh-rsa AAAAignored
+";
+
+    assert_eq!(
+        parse_console_host_keys(output),
+        vec![
+            "ecdsa-sha2-nistp256 AAAAE2VjZHNh".to_owned(),
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5".to_owned()
+        ]
+    );
+    assert!(parse_console_host_keys("booting").is_empty());
+}
+
+#[test]
+fn repinning_moves_keys_to_the_new_host() {
+    let contents = "1.1.1.1 ssh-ed25519 AAAA\n9.9.9.9 ssh-rsa BBBB\n";
+
+    assert_eq!(
+        repin_lines(contents, "1.1.1.1", "2.2.2.2"),
+        Some("2.2.2.2 ssh-ed25519 AAAA\n".to_owned())
+    );
+    assert_eq!(repin_lines(contents, "3.3.3.3", "2.2.2.2"), None);
+}
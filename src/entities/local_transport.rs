@@ -0,0 +1,197 @@
+use super::transport::{OnLine, OutputTail, WriteData, OUTPUT_TAIL_LINES};
+use crate::{BuilderTransport, CommandOutput, OutputStream, TransportError};
+use std::ffi::OsString;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+/// Runs commands with the local shell from a directory standing in for ec2-user's home
+/// directory, so code using a transport can be tested without an instance
+pub struct LocalTransport {
+    home: PathBuf,
+    path: OsString,
+}
+
+impl LocalTransport {
+    pub fn new(home: &Path) -> LocalTransport {
+        LocalTransport {
+            home: home.to_owned(),
+            path: std::env::var_os("PATH").unwrap_or_default(),
+        }
+    }
+
+    /// Replace commands such as docker with the given `(name, script)` stubs, which are
+    /// written to `stub-bin` in the home directory & found before anything else on the PATH
+    #[cfg(test)]
+    pub fn with_stubs(mut self, stubs: &[(&str, &str)]) -> LocalTransport {
+        let bin = self.home.join("stub-bin");
+        fs::create_dir_all(&bin).unwrap();
+        for (name, script) in stubs {
+            let path = bin.join(name);
+            fs::write(&path, script).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+
+        let mut paths = vec![bin];
+        paths.extend(std::env::split_paths(&self.path));
+        self.path = std::env::join_paths(paths).expect("Invalid PATH");
+        self
+    }
+
+    fn command(&self, command: &str) -> Command {
+        let mut shell = Command::new("bash");
+        shell
+            .arg("-c")
+            .arg(command)
+            .current_dir(&self.home)
+            .env("HOME", &self.home)
+            .env("PATH", &self.path);
+        shell
+    }
+}
+
+fn local_error<E: std::fmt::Display>(err: E) -> TransportError {
+    TransportError::Local(err.to_string())
+}
+
+#[async_trait::async_trait]
+impl BuilderTransport for LocalTransport {
+    async fn send_data(
+        &self,
+        data: &[u8],
+        remote_filename: &str,
+        mode: i32,
+    ) -> Result<(), TransportError> {
+        // Like scp the directory has to exist already
+        let path = self.home.join(remote_filename);
+        fs::write(&path, data).map_err(local_error)?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode as u32)).map_err(local_error)
+    }
+
+    async fn read_file(&self, remote_filename: &str) -> Result<Option<Vec<u8>>, TransportError> {
+        match fs::read(self.home.join(remote_filename)) {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(local_error(err)),
+        }
+    }
+
+    async fn exists(&self, remote_path: &str) -> Result<bool, TransportError> {
+        Ok(self.home.join(remote_path).exists())
+    }
+
+    async fn send_stream(
+        &self,
+        remote_command: &str,
+        write_data: &mut WriteData<'_>,
+    ) -> Result<(), TransportError> {
+        let mut child = self
+            .command(remote_command)
+            .stdin(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(local_error)?;
+
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        write_data(&mut stdin).map_err(local_error)?;
+        drop(stdin);
+
+        let output = child.wait_with_output().map_err(local_error)?;
+        if !output.status.success() {
+            return Err(TransportError::Local(format!(
+                "{} failed: {}",
+                remote_command,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Output is passed on once the command finishes, stdout first
+    async fn run_command_streamed(
+        &self,
+        command: &str,
+        on_line: &mut OnLine<'_>,
+    ) -> Result<CommandOutput, TransportError> {
+        self.run_command_with_input(command, &[], on_line).await
+    }
+
+    async fn run_command_with_input(
+        &self,
+        command: &str,
+        input: &[u8],
+        on_line: &mut OnLine<'_>,
+    ) -> Result<CommandOutput, TransportError> {
+        let mut child = self
+            .command(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(local_error)?;
+
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        stdin.write_all(input).map_err(local_error)?;
+        drop(stdin);
+
+        let output = child.wait_with_output().map_err(local_error)?;
+
+        let mut tail = OutputTail::new(OUTPUT_TAIL_LINES);
+        for (stream, data) in [
+            (OutputStream::Stdout, &output.stdout),
+            (OutputStream::Stderr, &output.stderr),
+        ] {
+            for line in String::from_utf8_lossy(data).lines() {
+                on_line(stream, line);
+                tail.push(line.to_owned());
+            }
+        }
+
+        Ok(CommandOutput {
+            exit_status: output.status.code().unwrap_or(-1),
+            tail: tail.into_lines(),
+        })
+    }
+
+    async fn wait_until_ready(&self, _timeout: Duration) -> Result<(), TransportError> {
+        Ok(())
+    }
+
+    fn connect_command(&self) -> String {
+        format!("cd {}", self.home.display())
+    }
+}
+
+#[tokio::test]
+async fn local_transport_runs_commands_from_the_home_dir() {
+    let home = tempfile::tempdir().unwrap();
+    let transport = LocalTransport::new(home.path());
+
+    transport
+        .send_stream("mkdir -p dir && cat > dir/file", &mut |writer| {
+            writer.write_all(b"data")
+        })
+        .await
+        .unwrap();
+
+    assert!(transport.exists("dir").await.unwrap());
+    assert_eq!(
+        transport.read_file("dir/file").await.unwrap(),
+        Some(b"data".to_vec())
+    );
+    assert_eq!(transport.read_file("missing").await.unwrap(), None);
+
+    let mut lines = vec![];
+    let output = transport
+        .run_command_streamed("cat dir/file; echo; exit 4", &mut |_, line| {
+            lines.push(line.to_owned())
+        })
+        .await
+        .unwrap();
+    assert_eq!(output.exit_status, 4);
+    assert_eq!(lines, vec!["data".to_owned()]);
+}
@@ -1,15 +1,60 @@
+mod architecture;
+mod aws_clients;
+mod backoff;
 mod cfn_client;
+mod cidr;
 mod config;
+mod context_manifest;
+mod credentials;
 mod docker_ignore;
 mod ec2_client;
+mod git_tags;
+mod host_keys;
+#[cfg(test)]
+mod local_transport;
+mod public_ip;
+mod region;
+mod registry_credentials;
+mod registry_uri;
+mod script;
+mod shell;
 mod ssh_client;
+mod ssm_client;
 mod sts_client;
 mod tag;
+mod template;
+mod transport;
 
+pub use architecture::{architecture_parser, architecture_validator, Architecture};
+pub use aws_clients::AwsClientFactory;
+pub use backoff::Backoff;
 pub use cfn_client::{CfnClient, DeployError, SimpleParameter};
+pub use cidr::{cidr_parser, cidr_validator};
 pub use config::{Config, ConfigWriteError};
-pub use docker_ignore::DockerIgnore;
-pub use ec2_client::EC2Client;
+pub use context_manifest::{context_name, ContextManifest, ManifestDiff};
+pub use credentials::CredentialsChain;
+pub use docker_ignore::{DockerIgnore, DockerIgnoreError};
+pub use ec2_client::{EC2Client, InstanceDetails, InstanceWaitError};
+pub use git_tags::{git_tags, GitTagError};
+pub use host_keys::{parse_console_host_keys, HostKeyError, HostKeys};
+#[cfg(test)]
+pub use local_transport::LocalTransport;
+pub use public_ip::get_public_ip;
+pub use region::{region_parser, region_validator};
+pub use registry_credentials::{
+    find_registry_credentials, RegistryCredentials, RegistryCredentialsError,
+};
+pub use registry_uri::{
+    is_valid_tag, registry_uri_validator, RegistryKind, RegistryUri, RegistryUriError,
+};
+pub use script::{Script, ScriptCommand};
+pub use shell::shell_quote;
 pub use ssh_client::{SSHClient, SSHClientError};
+pub use ssm_client::{SSMClient, SSMClientError};
 pub use sts_client::get_current_account_no;
 pub use tag::{tag_parser, tags_validator, Tag};
+pub use template::Template;
+pub use transport::{
+    create_transport, transport_parser, transport_validator, BuilderTransport, CommandOutput,
+    OutputStream, TransportError, TransportKind,
+};
@@ -0,0 +1,45 @@
+use rusoto_core::signature::SignedRequest;
+use rusoto_core::{Client, HttpClient, Region};
+use std::net::Ipv4Addr;
+
+/// Find the public IPv4 address this machine connects to AWS from
+pub async fn get_public_ip() -> Result<String, String> {
+    // checkip is a plain HTTPS endpoint so the request doesn't need signing
+    let mut request = SignedRequest::new("GET", "checkip", &Region::UsEast1, "/");
+    request.set_hostname(Some("checkip.amazonaws.com".to_owned()));
+
+    let client =
+        Client::new_not_signing(HttpClient::new().expect("Failed to create request dispatcher"));
+    let response = client
+        .sign_and_dispatch(request)
+        .await
+        .map_err(|err| format!("Failed to look up public IP: {:?}", err))?
+        .buffer()
+        .await
+        .map_err(|err| format!("Failed to look up public IP: {}", err))?;
+
+    if !response.status.is_success() {
+        return Err(format!(
+            "Failed to look up public IP, status {}",
+            response.status
+        ));
+    }
+
+    parse_public_ip(response.body_as_str())
+}
+
+fn parse_public_ip(body: &str) -> Result<String, String> {
+    body.trim()
+        .parse::<Ipv4Addr>()
+        .map(|ip| ip.to_string())
+        .map_err(|_| format!("Unexpected public IP response: {}", body.trim()))
+}
+
+#[test]
+fn public_ip_response_is_trimmed_and_validated() {
+    assert_eq!(
+        parse_public_ip("203.0.113.7\n"),
+        Ok("203.0.113.7".to_owned())
+    );
+    assert!(parse_public_ip("<html>").is_err());
+}
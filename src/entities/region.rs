@@ -0,0 +1,22 @@
+use rusoto_core::Region;
+use std::str::FromStr;
+
+pub fn region_validator(maybe_region: String) -> Result<(), String> {
+    region_parser(&maybe_region).map(|_| ())
+}
+
+pub fn region_parser(maybe_region: &str) -> Result<Region, String> {
+    Region::from_str(maybe_region).map_err(|_| format!("Region ({}) is not valid", maybe_region))
+}
+
+#[test]
+fn region_parser_should_accept_known_regions() {
+    assert_eq!(region_parser("eu-west-1"), Ok(Region::EuWest1));
+    assert_eq!(region_parser("ap-southeast-2"), Ok(Region::ApSoutheast2));
+}
+
+#[test]
+fn region_parser_should_error_on_unknown_region() {
+    assert!(region_parser("moon-base-1").is_err());
+    assert!(region_parser("").is_err());
+}
@@ -0,0 +1,290 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+// Docker keeps Docker Hub credentials under its old index URL
+const DOCKER_HUB_HOST: &str = "docker.io";
+const DOCKER_HUB_SERVER: &str = "https://index.docker.io/v1/";
+
+/// A username & password for `docker login`
+#[derive(Clone, Deserialize, PartialEq)]
+pub struct RegistryCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+// Never print the password
+impl std::fmt::Debug for RegistryCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RegistryCredentials")
+            .field("username", &self.username)
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+pub enum RegistryCredentialsError {
+    NotFound(String),
+    ReadFailed(String),
+    HelperFailed(String),
+    Invalid(String),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DockerConfig {
+    #[serde(default)]
+    auths: HashMap<String, DockerAuth>,
+    creds_store: Option<String>,
+    #[serde(default)]
+    cred_helpers: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct DockerAuth {
+    auth: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HelperCredentials {
+    username: String,
+    secret: String,
+}
+
+/// Find the credentials for a registry `host` on this machine. In order these come from
+/// - `CBUILDER_REGISTRY_<HOST>_USERNAME` & `CBUILDER_REGISTRY_<HOST>_PASSWORD`, where `<HOST>`
+///   is the host in upper case with other characters replaced by `_`
+/// - `registry-credentials.yml` in `working_dir`, a map of hosts to a username & password
+/// - the docker config, including its credential helpers
+pub fn find_registry_credentials(
+    host: &str,
+    working_dir: &Path,
+) -> Result<RegistryCredentials, RegistryCredentialsError> {
+    let credentials = match from_env(host, |name| std::env::var(name).ok()) {
+        Some(credentials) => Some(credentials),
+        None => from_credentials_file(host, &working_dir.join("registry-credentials.yml"))?,
+    };
+    let credentials = match credentials {
+        Some(credentials) => Some(credentials),
+        None => from_docker_config(host, &docker_config_path())?,
+    };
+
+    let credentials =
+        credentials.ok_or_else(|| RegistryCredentialsError::NotFound(host.to_owned()))?;
+
+    // The credentials are passed to the builder a line at a time
+    if credentials.username.contains('\n') || credentials.password.contains('\n') {
+        return Err(RegistryCredentialsError::Invalid(format!(
+            "The credentials for {} contain a newline",
+            host
+        )));
+    }
+
+    Ok(credentials)
+}
+
+fn from_env<F: Fn(&str) -> Option<String>>(host: &str, var: F) -> Option<RegistryCredentials> {
+    let prefix: String = host
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    Some(RegistryCredentials {
+        username: var(&format!("CBUILDER_REGISTRY_{}_USERNAME", prefix))?,
+        password: var(&format!("CBUILDER_REGISTRY_{}_PASSWORD", prefix))?,
+    })
+}
+
+fn from_credentials_file(
+    host: &str,
+    path: &Path,
+) -> Result<Option<RegistryCredentials>, RegistryCredentialsError> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let contents = std::fs::read_to_string(path)
+        .map_err(|err| RegistryCredentialsError::ReadFailed(err.to_string()))?;
+    let mut hosts: HashMap<String, RegistryCredentials> =
+        serde_yaml::from_str(&contents).map_err(|err| {
+            RegistryCredentialsError::ReadFailed(format!("{}: {}", path.display(), err))
+        })?;
+
+    Ok(hosts.remove(host))
+}
+
+fn docker_config_path() -> PathBuf {
+    match std::env::var_os("DOCKER_CONFIG") {
+        Some(dir) => PathBuf::from(dir).join("config.json"),
+        None => dirs::home_dir()
+            .unwrap_or_default()
+            .join(".docker")
+            .join("config.json"),
+    }
+}
+
+fn from_docker_config(
+    host: &str,
+    path: &Path,
+) -> Result<Option<RegistryCredentials>, RegistryCredentialsError> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let contents = std::fs::read_to_string(path)
+        .map_err(|err| RegistryCredentialsError::ReadFailed(err.to_string()))?;
+    let config: DockerConfig = serde_json::from_str(&contents).map_err(|err| {
+        RegistryCredentialsError::ReadFailed(format!("{}: {}", path.display(), err))
+    })?;
+
+    let server = if host == DOCKER_HUB_HOST {
+        DOCKER_HUB_SERVER
+    } else {
+        host
+    };
+
+    // Like docker, a helper takes priority over the credentials in the config
+    let helper = config
+        .cred_helpers
+        .get(server)
+        .or(config.creds_store.as_ref());
+    if let Some(helper) = helper {
+        if let Some(credentials) = from_helper(helper, server)? {
+            return Ok(Some(credentials));
+        }
+    }
+
+    let auth = [
+        server.to_owned(),
+        format!("https://{}", server),
+        format!("https://{}/v1/", server),
+    ]
+    .iter()
+    .find_map(|key| config.auths.get(key).and_then(|auth| auth.auth.clone()));
+
+    auth.map(|auth| decode_auth(&auth)).transpose()
+}
+
+/// Ask a docker credential helper for the credentials, returning `None` if it has none
+fn from_helper(
+    helper: &str,
+    server: &str,
+) -> Result<Option<RegistryCredentials>, RegistryCredentialsError> {
+    let program = format!("docker-credential-{}", helper);
+    let mut child = Command::new(&program)
+        .arg("get")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|err| RegistryCredentialsError::HelperFailed(format!("{}: {}", program, err)))?;
+
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    stdin
+        .write_all(server.as_bytes())
+        .map_err(|err| RegistryCredentialsError::HelperFailed(format!("{}: {}", program, err)))?;
+    drop(stdin);
+
+    let output = child
+        .wait_with_output()
+        .map_err(|err| RegistryCredentialsError::HelperFailed(format!("{}: {}", program, err)))?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+
+    let credentials: HelperCredentials = serde_json::from_slice(&output.stdout)
+        .map_err(|err| RegistryCredentialsError::HelperFailed(format!("{}: {}", program, err)))?;
+    Ok(Some(RegistryCredentials {
+        username: credentials.username,
+        password: credentials.secret,
+    }))
+}
+
+// Auths are base64 encoded `username:password`
+fn decode_auth(auth: &str) -> Result<RegistryCredentials, RegistryCredentialsError> {
+    let invalid = || RegistryCredentialsError::Invalid("Docker config auth is invalid".to_owned());
+
+    let decoded = base64::decode(auth).map_err(|_| invalid())?;
+    let decoded = String::from_utf8(decoded).map_err(|_| invalid())?;
+    let (username, password) = decoded.split_once(':').ok_or_else(invalid)?;
+
+    Ok(RegistryCredentials {
+        username: username.to_owned(),
+        password: password.to_owned(),
+    })
+}
+
+#[test]
+fn credentials_are_read_from_host_specific_variables() {
+    let var = |name: &str| match name {
+        "CBUILDER_REGISTRY_HARBOR_INTERNAL_5000_USERNAME" => Some("robot".to_owned()),
+        "CBUILDER_REGISTRY_HARBOR_INTERNAL_5000_PASSWORD" => Some("secret".to_owned()),
+        _ => None,
+    };
+
+    assert_eq!(
+        from_env("harbor.internal:5000", var),
+        Some(RegistryCredentials {
+            username: "robot".to_owned(),
+            password: "secret".to_owned()
+        })
+    );
+    assert_eq!(from_env("ghcr.io", var), None);
+}
+
+#[test]
+fn credentials_are_read_from_the_files() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let credentials_file = dir.path().join("registry-credentials.yml");
+    std::fs::write(
+        &credentials_file,
+        "ghcr.io:\n  username: me\n  password: ghp_token\n",
+    )
+    .unwrap();
+    assert_eq!(
+        from_credentials_file("ghcr.io", &credentials_file)
+            .unwrap()
+            .map(|credentials| credentials.password),
+        Some("ghp_token".to_owned())
+    );
+    assert!(from_credentials_file("quay.io", &credentials_file)
+        .unwrap()
+        .is_none());
+
+    // "me:hunter2" & "hub:pass"
+    let docker_config = dir.path().join("config.json");
+    std::fs::write(
+        &docker_config,
+        r#"{"auths": {
+            "https://ghcr.io": {"auth": "bWU6aHVudGVyMg=="},
+            "https://index.docker.io/v1/": {"auth": "aHViOnBhc3M="}
+        }}"#,
+    )
+    .unwrap();
+    assert_eq!(
+        from_docker_config("ghcr.io", &docker_config).unwrap(),
+        Some(RegistryCredentials {
+            username: "me".to_owned(),
+            password: "hunter2".to_owned()
+        })
+    );
+    assert_eq!(
+        from_docker_config("docker.io", &docker_config)
+            .unwrap()
+            .map(|credentials| credentials.username),
+        Some("hub".to_owned())
+    );
+    assert!(from_docker_config("quay.io", &docker_config)
+        .unwrap()
+        .is_none());
+}
@@ -0,0 +1,263 @@
+use super::region_parser;
+use regex::Regex;
+use rusoto_core::Region;
+use std::fmt;
+
+// Images without a registry host are on Docker Hub
+const DOCKER_HUB_HOST: &str = "docker.io";
+
+#[derive(Debug, PartialEq)]
+pub enum RegistryUriError {
+    InvalidHost(String),
+    InvalidAccount(String),
+    InvalidRegion(String),
+    InvalidRepository(String),
+    InvalidTag(String),
+}
+
+impl fmt::Display for RegistryUriError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistryUriError::InvalidHost(host) => write!(
+                f,
+                "Registry host ({}) is not valid. Registry URIs look like \
+<account>.dkr.ecr.<region>.amazonaws.com/<repository>[:tag] or <host>/<repository>[:tag]",
+                host
+            ),
+            RegistryUriError::InvalidAccount(account) => {
+                write!(f, "Account ({}) must be a 12 digit account number", account)
+            }
+            RegistryUriError::InvalidRegion(region) => {
+                write!(f, "Region ({}) is not valid", region)
+            }
+            RegistryUriError::InvalidRepository(repository) => write!(
+                f,
+                "Repository ({}) must be lowercase letters, digits & separators",
+                repository
+            ),
+            RegistryUriError::InvalidTag(tag) => {
+                write!(f, "Tag ({}) is not a valid docker tag", tag)
+            }
+        }
+    }
+}
+
+/// How the builder logs in to a registry
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegistryKind {
+    /// Logged in to with the push role of the account
+    Ecr { account: String, region: Region },
+    /// Logged in to with credentials found locally
+    Generic,
+}
+
+/// The URI of a repository, optionally with a tag
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegistryUri {
+    kind: RegistryKind,
+    host: String,
+    repository: String,
+    tag: Option<String>,
+}
+
+impl RegistryUri {
+    pub fn parse(uri: &str) -> Result<RegistryUri, RegistryUriError> {
+        // Like docker, the first segment is only a host if it looks like one
+        let (host, path) = match uri.split_once('/') {
+            Some((host, path)) if looks_like_host(host) => (host, path.to_owned()),
+            // A host on its own is missing the repository
+            None if looks_like_host(uri) => (uri, "".to_owned()),
+            _ => (DOCKER_HUB_HOST, uri.to_owned()),
+        };
+        // Official Docker Hub images live under library/
+        let path = if host == DOCKER_HUB_HOST && !path.contains('/') {
+            format!("library/{}", path)
+        } else {
+            path
+        };
+
+        let kind = parse_kind(host)?;
+
+        // The host has been split off so a port can't be mistaken for a tag
+        let (repository, tag) = match path.rsplit_once(':') {
+            Some((repository, tag)) => (repository, Some(tag.to_owned())),
+            None => (path.as_str(), None),
+        };
+
+        let valid_repository =
+            Regex::new(r"^[a-z0-9]+([._-][a-z0-9]+)*(/[a-z0-9]+([._-][a-z0-9]+)*)*$").unwrap();
+        if !valid_repository.is_match(repository) {
+            return Err(RegistryUriError::InvalidRepository(repository.to_owned()));
+        }
+
+        if let Some(tag) = tag.as_ref().filter(|tag| !is_valid_tag(tag)) {
+            return Err(RegistryUriError::InvalidTag(tag.clone()));
+        }
+
+        Ok(RegistryUri {
+            kind,
+            host: host.to_owned(),
+            repository: repository.to_owned(),
+            tag,
+        })
+    }
+
+    pub fn get_kind(&self) -> &RegistryKind {
+        &self.kind
+    }
+
+    /// The registry host which docker logs in to
+    pub fn get_host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn get_repository(&self) -> &str {
+        &self.repository
+    }
+
+    pub fn get_tag(&self) -> Option<&str> {
+        self.tag.as_deref()
+    }
+
+    /// The image reference for `tag` in this repository
+    pub fn image(&self, tag: &str) -> String {
+        format!("{}/{}:{}", self.host, self.repository, tag)
+    }
+}
+
+fn looks_like_host(segment: &str) -> bool {
+    segment.contains('.') || segment.contains(':') || segment == "localhost"
+}
+
+fn parse_kind(host: &str) -> Result<RegistryKind, RegistryUriError> {
+    let ecr_host = Regex::new(r"^([^.]+)\.dkr\.ecr\.([^.]+)\.amazonaws\.com(\.cn)?$").unwrap();
+    let captures = match ecr_host.captures(host) {
+        Some(captures) => captures,
+        None => {
+            let valid_host =
+                Regex::new(r"^[A-Za-z0-9]([A-Za-z0-9.-]*[A-Za-z0-9])?(:[0-9]+)?$").unwrap();
+            return if valid_host.is_match(host) {
+                Ok(RegistryKind::Generic)
+            } else {
+                Err(RegistryUriError::InvalidHost(host.to_owned()))
+            };
+        }
+    };
+
+    let account = captures[1].to_owned();
+    if !Regex::new(r"^[0-9]{12}$").unwrap().is_match(&account) {
+        return Err(RegistryUriError::InvalidAccount(account));
+    }
+
+    // China regions are only reachable through amazonaws.com.cn
+    let region_name = &captures[2];
+    let is_china = captures.get(3).is_some();
+    let region = region_parser(region_name)
+        .ok()
+        .filter(|_| is_china == region_name.starts_with("cn-"))
+        .ok_or_else(|| RegistryUriError::InvalidRegion(region_name.to_owned()))?;
+
+    Ok(RegistryKind::Ecr { account, region })
+}
+
+pub fn registry_uri_validator(maybe_uri: String) -> Result<(), String> {
+    RegistryUri::parse(&maybe_uri)
+        .map(|_| ())
+        .map_err(|err| err.to_string())
+}
+
+/// Docker tags are `[A-Za-z0-9_][A-Za-z0-9_.-]{0,127}`
+pub fn is_valid_tag(tag: &str) -> bool {
+    Regex::new(r"^[A-Za-z0-9_][A-Za-z0-9_.-]{0,127}$")
+        .unwrap()
+        .is_match(tag)
+}
+
+#[test]
+fn registry_uris_are_split_into_their_parts() {
+    let uri =
+        RegistryUri::parse("123456789012.dkr.ecr.ap-southeast-2.amazonaws.com/team/app").unwrap();
+    assert_eq!(
+        uri.get_kind(),
+        &RegistryKind::Ecr {
+            account: "123456789012".to_owned(),
+            region: Region::ApSoutheast2
+        }
+    );
+    assert_eq!(
+        uri.get_host(),
+        "123456789012.dkr.ecr.ap-southeast-2.amazonaws.com"
+    );
+    assert_eq!(uri.get_repository(), "team/app");
+    assert_eq!(uri.get_tag(), None);
+    assert_eq!(
+        uri.image("v1"),
+        "123456789012.dkr.ecr.ap-southeast-2.amazonaws.com/team/app:v1"
+    );
+
+    let tagged =
+        RegistryUri::parse("123456789012.dkr.ecr.cn-north-1.amazonaws.com.cn/app:v1.2").unwrap();
+    assert!(matches!(
+        tagged.get_kind(),
+        RegistryKind::Ecr {
+            region: Region::CnNorth1,
+            ..
+        }
+    ));
+    assert_eq!(tagged.get_repository(), "app");
+    assert_eq!(tagged.get_tag(), Some("v1.2"));
+}
+
+#[test]
+fn other_registries_are_parsed_like_docker_does() {
+    let ghcr = RegistryUri::parse("ghcr.io/owner/app:v1").unwrap();
+    assert_eq!(ghcr.get_kind(), &RegistryKind::Generic);
+    assert_eq!(ghcr.get_host(), "ghcr.io");
+    assert_eq!(ghcr.get_repository(), "owner/app");
+    assert_eq!(ghcr.get_tag(), Some("v1"));
+
+    let harbor = RegistryUri::parse("harbor.internal:5000/team/app").unwrap();
+    assert_eq!(harbor.get_host(), "harbor.internal:5000");
+    assert_eq!(harbor.get_tag(), None);
+
+    let hub = RegistryUri::parse("owner/app").unwrap();
+    assert_eq!(hub.image("latest"), "docker.io/owner/app:latest");
+    assert_eq!(
+        RegistryUri::parse("nginx").unwrap().image("latest"),
+        "docker.io/library/nginx:latest"
+    );
+}
+
+#[test]
+fn registry_uris_are_rejected_with_the_part_that_is_wrong() {
+    let parse = |uri: &str| RegistryUri::parse(uri).err();
+
+    assert_eq!(
+        parse("ghcr.io;reboot/owner/app"),
+        Some(RegistryUriError::InvalidHost("ghcr.io;reboot".to_owned()))
+    );
+    assert_eq!(
+        parse("12345.dkr.ecr.eu-west-1.amazonaws.com/app"),
+        Some(RegistryUriError::InvalidAccount("12345".to_owned()))
+    );
+    assert_eq!(
+        parse("123456789012.dkr.ecr.not-a-region.amazonaws.com/app"),
+        Some(RegistryUriError::InvalidRegion("not-a-region".to_owned()))
+    );
+    assert_eq!(
+        parse("123456789012.dkr.ecr.eu-west-1.amazonaws.com.cn/app"),
+        Some(RegistryUriError::InvalidRegion("eu-west-1".to_owned()))
+    );
+    assert_eq!(
+        parse("123456789012.dkr.ecr.eu-west-1.amazonaws.com/App;reboot"),
+        Some(RegistryUriError::InvalidRepository("App;reboot".to_owned()))
+    );
+    assert_eq!(
+        parse("123456789012.dkr.ecr.eu-west-1.amazonaws.com"),
+        Some(RegistryUriError::InvalidRepository("".to_owned()))
+    );
+    assert_eq!(
+        parse("123456789012.dkr.ecr.eu-west-1.amazonaws.com/app:.bad"),
+        Some(RegistryUriError::InvalidTag(".bad".to_owned()))
+    );
+}
@@ -0,0 +1,225 @@
+use super::shell_quote;
+
+/// A command for a `Script`. Every word is quoted so values are never interpreted by the shell.
+pub struct ScriptCommand {
+    words: Vec<String>,
+}
+
+impl ScriptCommand {
+    pub fn new(program: &str) -> ScriptCommand {
+        ScriptCommand {
+            words: vec![quote_word(program)],
+        }
+    }
+
+    pub fn arg<S: AsRef<str>>(mut self, arg: S) -> ScriptCommand {
+        self.words.push(quote_word(arg.as_ref()));
+        self
+    }
+
+    pub fn args<I, S>(mut self, args: I) -> ScriptCommand
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.words
+            .extend(args.into_iter().map(|arg| quote_word(arg.as_ref())));
+        self
+    }
+
+    /// A path relative to the home directory of the user running the script
+    pub fn home_path(mut self, path: &str) -> ScriptCommand {
+        self.words.push(home_path(path));
+        self
+    }
+
+    /// The value of a variable set earlier in the script
+    pub fn var(mut self, name: &str) -> ScriptCommand {
+        assert!(
+            is_variable_name(name),
+            "{} is not a valid variable name",
+            name
+        );
+        self.words.push(format!("\"${}\"", name));
+        self
+    }
+
+    fn render(&self) -> String {
+        self.words.join(" ")
+    }
+}
+
+/// A bash script which stops at the first failing command & logs each one
+pub struct Script {
+    lines: Vec<String>,
+}
+
+impl Default for Script {
+    fn default() -> Self {
+        Script::new()
+    }
+}
+
+impl Script {
+    pub fn new() -> Script {
+        Script {
+            lines: vec!["#!/bin/bash -eux".to_owned()],
+        }
+    }
+
+    pub fn run(&mut self, command: ScriptCommand) -> &mut Script {
+        self.lines.push(command.render());
+        self
+    }
+
+    /// Run `from` with its output piped into `to`
+    pub fn pipe(&mut self, from: ScriptCommand, to: ScriptCommand) -> &mut Script {
+        self.lines
+            .push(format!("{} | {}", from.render(), to.render()));
+        self
+    }
+
+    /// Run `commands` only if `check` fails
+    pub fn if_fails(&mut self, check: ScriptCommand, commands: Vec<ScriptCommand>) -> &mut Script {
+        self.lines.push(format!("if ! {}; then", check.render()));
+        self.lines
+            .extend(commands.iter().map(|command| command.render()));
+        self.lines.push("fi".to_owned());
+        self
+    }
+
+    /// Run `commands` when the script exits, whether or not it succeeded
+    pub fn on_exit(&mut self, commands: Vec<ScriptCommand>) -> &mut Script {
+        let commands: Vec<String> = commands.iter().map(|command| command.render()).collect();
+        self.lines
+            .push(format!("trap {} EXIT", shell_quote(&commands.join("; "))));
+        self
+    }
+
+    /// Export `name` as a path relative to the home directory
+    pub fn export_home_path(&mut self, name: &str, path: &str) -> &mut Script {
+        assert!(
+            is_variable_name(name),
+            "{} is not a valid variable name",
+            name
+        );
+        self.lines
+            .push(format!("export {}={}", name, home_path(path)));
+        self
+    }
+
+    /// Add fixed lines of bash. Only static strings are accepted so values can't be
+    /// interpolated into them.
+    pub fn raw(&mut self, lines: &'static str) -> &mut Script {
+        self.lines.extend(lines.lines().map(|line| line.to_owned()));
+        self
+    }
+
+    pub fn render(&self) -> String {
+        self.lines.join("\n")
+    }
+}
+
+/// Leave words made only of characters bash treats literally unquoted, to keep scripts readable
+fn quote_word(word: &str) -> String {
+    let is_plain = !word.is_empty()
+        && word.chars().all(|c| {
+            c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '/' | ':' | ',' | '+' | '@')
+        });
+
+    if is_plain {
+        word.to_owned()
+    } else {
+        shell_quote(word)
+    }
+}
+
+// The tilde has to be outside the quotes to be expanded
+fn home_path(path: &str) -> String {
+    format!("~/{}", quote_word(path))
+}
+
+fn is_variable_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[test]
+fn script_commands_quote_every_word() {
+    let mut script = Script::new();
+    script
+        .on_exit(vec![
+            ScriptCommand::new("rm").arg("-rf").home_path("builds/1 2"),
+            ScriptCommand::new("touch").arg("done"),
+        ])
+        .export_home_path("AWS_CONFIG_FILE", "builds/1 2/aws-config")
+        .run(
+            ScriptCommand::new("docker")
+                .arg("build")
+                .args(["--build-arg", "A=$(reboot); `id`", "--label", "it's"])
+                .arg("."),
+        )
+        .pipe(
+            ScriptCommand::new("echo").arg(""),
+            ScriptCommand::new("cat"),
+        )
+        .if_fails(
+            ScriptCommand::new("test").args(["-e", "x y"]),
+            vec![ScriptCommand::new("touch").arg("x y")],
+        );
+
+    assert_eq!(
+        script.render(),
+        "#!/bin/bash -eux
+trap 'rm -rf ~/'\\''builds/1 2'\\''; touch done' EXIT
+export AWS_CONFIG_FILE=~/'builds/1 2/aws-config'
+docker build --build-arg 'A=$(reboot); `id`' --label 'it'\\''s' .
+echo '' | cat
+if ! test -e 'x y'; then
+touch 'x y'
+fi"
+    );
+}
+
+#[test]
+fn scripts_run_as_written() {
+    let home = tempfile::tempdir().unwrap();
+    let mut script = Script::new();
+    script
+        .raw("FROM_RAW=$(echo raw)")
+        .on_exit(vec![ScriptCommand::new("touch")
+            .home_path("exited $HOME")
+            .var("FROM_RAW")])
+        .export_home_path("TARGET", "a dir")
+        .run(ScriptCommand::new("mkdir").home_path("a dir"))
+        .pipe(
+            ScriptCommand::new("printenv").arg("TARGET"),
+            ScriptCommand::new("tee").arg("a dir/target"),
+        )
+        .pipe(
+            ScriptCommand::new("echo").arg("$(not run)"),
+            ScriptCommand::new("tee").arg("a dir/out"),
+        );
+
+    let status = std::process::Command::new("bash")
+        .arg("-c")
+        .arg(script.render())
+        .current_dir(home.path())
+        .env("HOME", home.path())
+        .output()
+        .unwrap()
+        .status;
+
+    assert!(status.success());
+    assert!(home.path().join("exited $HOME").exists());
+    assert!(home.path().join("raw").exists());
+    assert_eq!(
+        std::fs::read_to_string(home.path().join("a dir/out")).unwrap(),
+        "$(not run)\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(home.path().join("a dir/target")).unwrap(),
+        format!("{}/a dir\n", home.path().display())
+    );
+}
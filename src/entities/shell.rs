@@ -0,0 +1,10 @@
+/// Quote `value` as a single word for bash, so it is never expanded or split
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+#[test]
+fn quote_escapes_single_quotes() {
+    assert_eq!(shell_quote("echo hi"), "'echo hi'");
+    assert_eq!(shell_quote("echo 'hi'"), "'echo '\\''hi'\\'''");
+}
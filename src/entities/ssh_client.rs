@@ -1,19 +1,41 @@
-use ssh2::Session;
+use super::transport::{OnLine, OutputTail, WriteData, OUTPUT_TAIL_LINES};
+use crate::{
+    shell_quote, Backoff, BuilderTransport, CommandOutput, HostKeyError, HostKeys, OutputStream,
+    TransportError,
+};
+use ssh2::{Channel, Session};
 use std::convert::From;
-use std::fs::File;
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::{thread::sleep, time::Duration};
 
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long to keep trying to reconnect when the connection drops between commands
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Stops NAT gateways & firewalls dropping the connection during quiet parts of a build
+const KEEPALIVE_INTERVAL_SECS: u32 = 30;
+
+/// Runs commands on the builder over SSH. One session is shared by every command and is
+/// only opened when first needed.
 pub struct SSHClient {
     ip: String,
     private_key: PathBuf,
+    host_keys: HostKeys,
+    connect_timeout: Duration,
+    session: Mutex<Option<Session>>,
 }
 
 #[derive(Debug)]
 pub enum SSHClientError {
     IOError(String),
     SSHError(String),
+    RemoteCommandFailed(String),
+    NotReady(String),
+    HostKey(HostKeyError),
 }
 
 impl From<ssh2::Error> for SSHClientError {
@@ -22,51 +44,472 @@ impl From<ssh2::Error> for SSHClientError {
     }
 }
 
+impl From<HostKeyError> for SSHClientError {
+    fn from(error: HostKeyError) -> Self {
+        SSHClientError::HostKey(error)
+    }
+}
+
 impl From<std::io::Error> for SSHClientError {
     fn from(error: std::io::Error) -> Self {
         SSHClientError::IOError(error.to_string())
     }
 }
 
+// ssh2 is blocking so these run on the calling thread, as the rest of the commands do
+#[async_trait::async_trait]
+impl BuilderTransport for SSHClient {
+    async fn send_data(
+        &self,
+        data: &[u8],
+        remote_filename: &str,
+        mode: i32,
+    ) -> Result<(), TransportError> {
+        let mut remote_file = self.open(|session| {
+            session.scp_send(Path::new(remote_filename), mode, data.len() as u64, None)
+        })?;
+        remote_file.write_all(data).map_err(SSHClientError::from)?;
+
+        // Wait for the remote side to acknowledge the whole file
+        remote_file.send_eof().map_err(SSHClientError::from)?;
+        remote_file.wait_eof().map_err(SSHClientError::from)?;
+        remote_file.close().map_err(SSHClientError::from)?;
+        remote_file.wait_close().map_err(SSHClientError::from)?;
+
+        Ok(())
+    }
+
+    async fn read_file(&self, remote_filename: &str) -> Result<Option<Vec<u8>>, TransportError> {
+        Ok(self.read_file_blocking(remote_filename)?)
+    }
+
+    async fn exists(&self, remote_path: &str) -> Result<bool, TransportError> {
+        let output = self.run_command_streamed_blocking(
+            &format!("test -e {}", shell_quote(remote_path)),
+            &[],
+            &mut |_, _| {},
+        )?;
+        Ok(output.exit_status == 0)
+    }
+
+    /// Data is streamed into the command as it is produced, so nothing needs to be
+    /// buffered in memory or written to local disk first
+    async fn send_stream(
+        &self,
+        remote_command: &str,
+        write_data: &mut WriteData<'_>,
+    ) -> Result<(), TransportError> {
+        Ok(self.send_stream_blocking(remote_command, write_data)?)
+    }
+
+    /// Output is passed to `on_line` as soon as it arrives
+    async fn run_command_streamed(
+        &self,
+        command: &str,
+        on_line: &mut OnLine<'_>,
+    ) -> Result<CommandOutput, TransportError> {
+        Ok(self.run_command_streamed_blocking(command, &[], on_line)?)
+    }
+
+    async fn run_command_with_input(
+        &self,
+        command: &str,
+        input: &[u8],
+        on_line: &mut OnLine<'_>,
+    ) -> Result<CommandOutput, TransportError> {
+        Ok(self.run_command_streamed_blocking(command, input, on_line)?)
+    }
+
+    /// Wait until the instance accepts SSH connections and docker is running on it
+    async fn wait_until_ready(&self, timeout: Duration) -> Result<(), TransportError> {
+        let mut backoff = Backoff::new(Duration::from_secs(2), Duration::from_secs(10), timeout);
+
+        loop {
+            let error = match self.check_ready() {
+                Ok(()) => return Ok(()),
+                // Retrying won't change the key
+                Err(err @ SSHClientError::HostKey(HostKeyError::Mismatch(_))) => {
+                    return Err(err.into())
+                }
+                Err(err) => err,
+            };
+
+            match backoff.next_delay() {
+                Some(delay) => sleep(delay),
+                None => return Err(SSHClientError::NotReady(format!("{:?}", error)).into()),
+            }
+        }
+    }
+
+    fn connect_command(&self) -> String {
+        let key = self
+            .private_key
+            .canonicalize()
+            .unwrap_or_else(|_| self.private_key.clone());
+
+        format!(
+            "ssh -i {} -o UserKnownHostsFile={} -o StrictHostKeyChecking=accept-new ec2-user@{}",
+            key.display(),
+            self.host_keys.get_path().display(),
+            self.ip
+        )
+    }
+}
+
 impl SSHClient {
-    pub fn new(ip: String, private_key: PathBuf) -> SSHClient {
-        SSHClient { ip, private_key }
+    pub fn new(ip: String, private_key: PathBuf, host_keys: HostKeys) -> SSHClient {
+        SSHClient {
+            ip,
+            private_key,
+            host_keys,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            session: Mutex::new(None),
+        }
+    }
+
+    pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> SSHClient {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// Open a channel on the shared session, reconnecting if the connection was lost
+    fn open_channel(&self) -> Result<(Session, Channel), SSHClientError> {
+        self.open(|session| {
+            session
+                .channel_session()
+                .map(|channel| (session.clone(), channel))
+        })
+    }
+
+    /// Run `open` with the shared session. If it fails the session is assumed to be broken
+    /// & is replaced, backing off between attempts. Only opening is retried as commands may
+    /// not be safe to run twice.
+    fn open<T, F>(&self, open: F) -> Result<T, SSHClientError>
+    where
+        F: Fn(&Session) -> Result<T, ssh2::Error>,
+    {
+        let mut backoff = Backoff::new(
+            Duration::from_secs(1),
+            Duration::from_secs(5),
+            RECONNECT_TIMEOUT,
+        );
+
+        loop {
+            let error = match self.try_open(&open) {
+                Ok(result) => return Ok(result),
+                // Retrying won't change the key
+                Err(err @ SSHClientError::HostKey(_)) => return Err(err),
+                Err(err) => err,
+            };
+
+            match backoff.next_delay() {
+                Some(delay) => sleep(delay),
+                None => return Err(error),
+            }
+        }
+    }
+
+    fn try_open<T, F>(&self, open: &F) -> Result<T, SSHClientError>
+    where
+        F: Fn(&Session) -> Result<T, ssh2::Error>,
+    {
+        let mut cached = self.session.lock().expect("SSH session lock poisoned");
+        let session = match cached.as_ref() {
+            Some(session) => session.clone(),
+            None => {
+                let session = self.create_session()?;
+                *cached = Some(session.clone());
+                session
+            }
+        };
+
+        // A command which failed part way may have left the session non-blocking
+        session.set_blocking(true);
+        open(&session).map_err(|err| {
+            *cached = None;
+            err.into()
+        })
     }
 
-    pub fn send_file(
+    fn read_file_blocking(&self, remote_filename: &str) -> Result<Option<Vec<u8>>, SSHClientError> {
+        let (_, mut channel) = self.open_channel()?;
+        channel.exec(&format!(
+            "test -f {0} && cat {0}",
+            shell_quote(remote_filename)
+        ))?;
+
+        let mut data = Vec::new();
+        channel.read_to_end(&mut data)?;
+        channel.wait_close()?;
+
+        match channel.exit_status()? {
+            0 => Ok(Some(data)),
+            _ => Ok(None),
+        }
+    }
+
+    fn send_stream_blocking(
         &self,
-        local_file: &Path,
-        remote_filename: String,
+        remote_command: &str,
+        write_data: &mut WriteData<'_>,
     ) -> Result<(), SSHClientError> {
-        let mut file_contents = Vec::new();
-        let mut archive_file = File::open(local_file)?;
-        let size = archive_file.read_to_end(&mut file_contents)?;
-        let session = self.create_session()?;
-        let mut remote_file =
-            session.scp_send(Path::new(&remote_filename), 0o777, size as u64, None)?;
-        remote_file.write_all(&file_contents)?;
+        let (session, mut channel) = self.open_channel()?;
+        channel.exec(remote_command)?;
+
+        // Keep reading the command's output while writing, otherwise a command which writes
+        // more than the channel window to stderr stops reading its input & the write stalls
+        session.set_blocking(false);
+        let mut writer = DrainingWriter {
+            channel: &mut channel,
+            stderr: vec![],
+        };
+        let written = write_data(&mut writer);
+        // Sending EOF lets the command finish even when the data is incomplete
+        let eof = retry_would_block(|| writer.channel.send_eof());
+        let drained = writer.drain_until_eof();
+        let stderr = String::from_utf8_lossy(&writer.stderr).trim().to_owned();
+
+        session.set_blocking(true);
+        if let Err(err) = written.and(eof).and(drained) {
+            return Err(SSHClientError::RemoteCommandFailed(format!(
+                "{} failed: {}: {}",
+                remote_command, err, stderr
+            )));
+        }
+        channel.wait_close()?;
+
+        if channel.exit_status()? != 0 {
+            return Err(SSHClientError::RemoteCommandFailed(format!(
+                "{} failed: {}",
+                remote_command, stderr
+            )));
+        }
 
         Ok(())
     }
 
-    pub fn run_command(&self, command: String) -> Result<bool, SSHClientError> {
-        let session = self.create_session()?;
-        let mut channel = session.channel_session()?;
-        channel.exec(&command)?;
-        let mut s = String::new();
-        channel.read_to_string(&mut s)?;
+    fn run_command_streamed_blocking(
+        &self,
+        command: &str,
+        input: &[u8],
+        on_line: &mut OnLine<'_>,
+    ) -> Result<CommandOutput, SSHClientError> {
+        let (session, mut channel) = self.open_channel()?;
+        channel.exec(command)?;
+
+        // Input is small so it can be sent before any output is read
+        if !input.is_empty() {
+            channel.write_all(input)?;
+            channel.send_eof()?;
+        }
+
+        // Read stdout & stderr without blocking so neither can stall waiting on the other
+        session.set_blocking(false);
+
+        let mut stdout = LineBuffer::new();
+        let mut stderr = LineBuffer::new();
+        let mut tail = OutputTail::new(OUTPUT_TAIL_LINES);
+        let mut buffer = [0u8; 8192];
+
+        loop {
+            let stdout_read = read_available(&mut channel, &mut buffer)?;
+            for line in stdout.push(&buffer[..stdout_read]) {
+                on_line(OutputStream::Stdout, &line);
+                tail.push(line);
+            }
+
+            let stderr_read = read_available(&mut channel.stderr(), &mut buffer)?;
+            for line in stderr.push(&buffer[..stderr_read]) {
+                on_line(OutputStream::Stderr, &line);
+                tail.push(line);
+            }
+
+            if stdout_read == 0 && stderr_read == 0 {
+                if channel.eof() {
+                    break;
+                }
+                // Only sends once the interval has passed
+                if let Err(err) = session.keepalive_send() {
+                    let err = std::io::Error::from(err);
+                    if err.kind() != ErrorKind::WouldBlock {
+                        return Err(err.into());
+                    }
+                }
+                sleep(Duration::from_millis(20));
+            }
+        }
+
+        for (stream, line) in [
+            (OutputStream::Stdout, stdout.finish()),
+            (OutputStream::Stderr, stderr.finish()),
+        ] {
+            if let Some(line) = line {
+                on_line(stream, &line);
+                tail.push(line);
+            }
+        }
+
+        session.set_blocking(true);
+        channel.wait_close()?;
+
+        Ok(CommandOutput {
+            exit_status: channel.exit_status()?,
+            tail: tail.into_lines(),
+        })
+    }
+
+    fn check_ready(&self) -> Result<(), SSHClientError> {
+        let (_, mut channel) = self.open_channel()?;
+        channel.exec("docker info")?;
+
+        let mut output = Vec::new();
+        channel.read_to_end(&mut output)?;
         channel.wait_close()?;
-        let is_ok = channel.exit_status()? == 0;
-        Ok(is_ok)
+
+        if channel.exit_status()? != 0 {
+            return Err(SSHClientError::RemoteCommandFailed(
+                "docker info failed".to_owned(),
+            ));
+        }
+
+        Ok(())
     }
 
     fn create_session(&self) -> Result<Session, SSHClientError> {
-        let tcp = TcpStream::connect(self.ip.clone() + ":22")?;
+        let address = (self.ip.as_str(), 22)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| SSHClientError::IOError(format!("Could not resolve {}", self.ip)))?;
+        let tcp = TcpStream::connect_timeout(&address, self.connect_timeout)?;
+
         let mut session = Session::new()?;
         session.set_tcp_stream(tcp);
+
+        // Only time limit the handshake as builds can legitimately be quiet for a long time
+        session.set_timeout(self.connect_timeout.as_millis() as u32);
         session.handshake()?;
+        self.host_keys.verify(&session, &self.ip)?;
         session.userauth_pubkey_file("ec2-user", None, &self.private_key, None)?;
+        session.set_timeout(0);
+        session.set_keepalive(false, KEEPALIVE_INTERVAL_SECS);
 
         Ok(session)
     }
 }
+
+fn read_available<R: Read>(reader: &mut R, buffer: &mut [u8]) -> std::io::Result<usize> {
+    match reader.read(buffer) {
+        Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(0),
+        result => result,
+    }
+}
+
+/// Writes to a non-blocking channel, collecting its stderr & discarding its stdout whenever
+/// the remote side isn't ready for more data
+struct DrainingWriter<'a> {
+    channel: &'a mut Channel,
+    stderr: Vec<u8>,
+}
+
+impl DrainingWriter<'_> {
+    /// Read everything the command has output so far, returning true at the end of it
+    fn drain(&mut self) -> std::io::Result<bool> {
+        let mut buffer = [0u8; 8192];
+        loop {
+            let stdout_read = read_available(self.channel, &mut buffer)?;
+            let stderr_read = read_available(&mut self.channel.stderr(), &mut buffer)?;
+            self.stderr.extend_from_slice(&buffer[..stderr_read]);
+
+            if stdout_read == 0 && stderr_read == 0 {
+                return Ok(self.channel.eof());
+            }
+        }
+    }
+
+    fn drain_until_eof(&mut self) -> std::io::Result<()> {
+        while !self.drain()? {
+            sleep(Duration::from_millis(20));
+        }
+        Ok(())
+    }
+}
+
+impl Write for DrainingWriter<'_> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        loop {
+            self.drain()?;
+            match self.channel.write(data) {
+                Err(err) if err.kind() == ErrorKind::WouldBlock => sleep(Duration::from_millis(20)),
+                result => return result,
+            }
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        loop {
+            self.drain()?;
+            match self.channel.flush() {
+                Err(err) if err.kind() == ErrorKind::WouldBlock => sleep(Duration::from_millis(20)),
+                result => return result,
+            }
+        }
+    }
+}
+
+fn retry_would_block<F>(mut operation: F) -> std::io::Result<()>
+where
+    F: FnMut() -> Result<(), ssh2::Error>,
+{
+    loop {
+        match operation().map_err(std::io::Error::from) {
+            Err(err) if err.kind() == ErrorKind::WouldBlock => sleep(Duration::from_millis(20)),
+            result => return result,
+        }
+    }
+}
+
+/// Collects bytes from a stream and splits them into complete lines
+struct LineBuffer {
+    pending: Vec<u8>,
+}
+
+impl LineBuffer {
+    fn new() -> LineBuffer {
+        LineBuffer { pending: vec![] }
+    }
+
+    fn push(&mut self, data: &[u8]) -> Vec<String> {
+        self.pending.extend_from_slice(data);
+
+        let mut lines = vec![];
+        while let Some(index) = self.pending.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=index).collect();
+            lines.push(
+                String::from_utf8_lossy(&line[..line.len() - 1])
+                    .trim_end_matches('\r')
+                    .to_owned(),
+            );
+        }
+        lines
+    }
+
+    fn finish(&mut self) -> Option<String> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        let line = String::from_utf8_lossy(&self.pending).into_owned();
+        self.pending.clear();
+        Some(line)
+    }
+}
+
+#[test]
+fn line_buffer_only_returns_complete_lines() {
+    let mut buffer = LineBuffer::new();
+
+    assert_eq!(buffer.push(b"first\nsec"), vec!["first".to_owned()]);
+    assert_eq!(buffer.push(b"ond\r\n"), vec!["second".to_owned()]);
+    assert_eq!(buffer.push(b"third"), Vec::<String>::new());
+    assert_eq!(buffer.finish(), Some("third".to_owned()));
+    assert_eq!(buffer.finish(), None);
+}
@@ -0,0 +1,603 @@
+use super::transport::{OnLine, OutputTail, WriteData, OUTPUT_TAIL_LINES};
+use crate::{
+    shell_quote, AwsClientFactory, Backoff, BuilderTransport, CommandOutput, OutputStream,
+    TransportError,
+};
+use futures::TryStreamExt;
+use rusoto_core::{Region, RusotoError};
+use rusoto_s3::{DeleteObjectRequest, GetObjectRequest, PutObjectRequest, S3Client, S3};
+use rusoto_ssm::{
+    GetCommandInvocationError, GetCommandInvocationRequest, SendCommandRequest, Ssm, SsmClient,
+};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{thread::sleep, time::Duration};
+
+// Builds can take a long time so allow commands to run for up to 4 hours
+const EXECUTION_TIMEOUT_SECS: u64 = 4 * 60 * 60;
+
+// Staged files can hold secrets so they are encrypted with the account's S3 KMS key. The
+// bucket policy rejects anything uploaded without it.
+const STAGING_ENCRYPTION: &str = "aws:kms";
+
+// Exit status used by the read_file script when the file doesn't exist
+const FILE_MISSING_STATUS: i32 = 3;
+
+/// Runs commands on the builder with SSM Run Command, for accounts which don't allow
+/// inbound SSH. Files are passed through the staging bucket, which the instance role can
+/// read & write. Commands run as root so they are wrapped to run as ec2-user instead.
+pub struct SSMClient {
+    ssm: SsmClient,
+    s3: S3Client,
+    region: Region,
+    profile: String,
+    instance_id: String,
+    bucket: String,
+    transfer_count: AtomicUsize,
+}
+
+#[derive(Debug)]
+pub enum SSMClientError {
+    RequestFailed(String),
+    RemoteCommandFailed(String),
+    TimedOut(String),
+    NotReady(String),
+}
+
+/// The parts of a finished command invocation the transport uses
+#[derive(Debug)]
+struct Invocation {
+    response_code: i32,
+    standard_error_content: String,
+}
+
+#[async_trait::async_trait]
+impl BuilderTransport for SSMClient {
+    async fn send_data(
+        &self,
+        data: &[u8],
+        remote_filename: &str,
+        mode: i32,
+    ) -> Result<(), TransportError> {
+        let command = format!("cat > {0} && chmod {1:o} {0}", remote_filename, mode);
+        self.send_stream(&command, &mut |writer| writer.write_all(data))
+            .await
+    }
+
+    async fn read_file(&self, remote_filename: &str) -> Result<Option<Vec<u8>>, TransportError> {
+        let key = self.transfer_key();
+        let script = format!(
+            "set -o pipefail\n{} || exit {}\n{} | {}",
+            as_ec2_user(&format!("test -f {}", remote_filename)),
+            FILE_MISSING_STATUS,
+            as_ec2_user(&format!("cat {}", remote_filename)),
+            self.upload_command("-", &key),
+        );
+
+        let invocation = self.run_script(&script).await?;
+        match invocation.response_code {
+            0 => {
+                let data = self.get_object(&key).await;
+                self.delete_object(&key).await?;
+                Ok(Some(data?))
+            }
+            FILE_MISSING_STATUS => Ok(None),
+            _ => Err(SSMClientError::RemoteCommandFailed(format!(
+                "Reading {} failed: {}",
+                remote_filename,
+                invocation.standard_error_content.trim()
+            ))
+            .into()),
+        }
+    }
+
+    async fn exists(&self, remote_path: &str) -> Result<bool, TransportError> {
+        let invocation = self
+            .run_script(&as_ec2_user(&format!("test -e {}", remote_path)))
+            .await?;
+        Ok(invocation.response_code == 0)
+    }
+
+    /// The data is buffered & uploaded to the staging bucket before the command is run
+    async fn send_stream(
+        &self,
+        remote_command: &str,
+        write_data: &mut WriteData<'_>,
+    ) -> Result<(), TransportError> {
+        let mut data = Vec::new();
+        write_data(&mut data).map_err(|err| {
+            SSMClientError::RequestFailed(format!("Failed to buffer data: {}", err))
+        })?;
+
+        let key = self.transfer_key();
+        self.put_object(&key, data).await?;
+
+        let script = format!(
+            "set -o pipefail\n{} | {}",
+            self.download_command(&key, "-"),
+            as_ec2_user(remote_command)
+        );
+        let result = self.run_script(&script).await;
+        self.delete_object(&key).await?;
+
+        let invocation = result?;
+        if invocation.response_code != 0 {
+            return Err(SSMClientError::RemoteCommandFailed(format!(
+                "{} failed: {}",
+                remote_command,
+                invocation.standard_error_content.trim()
+            ))
+            .into());
+        }
+
+        Ok(())
+    }
+
+    /// SSM only returns output once the command has finished, so the lines are passed to
+    /// `on_line` all at once at the end. Stdout & stderr are combined.
+    async fn run_command_streamed(
+        &self,
+        command: &str,
+        on_line: &mut OnLine<'_>,
+    ) -> Result<CommandOutput, TransportError> {
+        Ok(self.run_logged(&as_ec2_user(command), on_line).await?)
+    }
+
+    /// The input is staged in the bucket, then read into memory & deleted from the bucket
+    /// before the command starts so nothing it runs can fetch it with the instance role
+    async fn run_command_with_input(
+        &self,
+        command: &str,
+        input: &[u8],
+        on_line: &mut OnLine<'_>,
+    ) -> Result<CommandOutput, TransportError> {
+        let key = self.transfer_key();
+        self.put_object(&key, input.to_vec()).await?;
+
+        // base64 keeps any bytes the shell variable couldn't hold
+        let pipeline = format!(
+            "(\ninput=$({download} | base64 -w0) || exit 1\n{remove} || exit 1\nprintf '%s' \"$input\" | base64 -d | {command}\n)",
+            download = self.download_command(&key, "-"),
+            remove = self.remove_command(&key),
+            command = as_ec2_user(command)
+        );
+        let result = self.run_logged(&pipeline, on_line).await;
+        self.delete_object(&key).await?;
+
+        Ok(result?)
+    }
+
+    /// Wait until the instance has registered with SSM and docker is running on it
+    async fn wait_until_ready(&self, timeout: Duration) -> Result<(), TransportError> {
+        let mut backoff = Backoff::new(Duration::from_secs(5), Duration::from_secs(15), timeout);
+
+        loop {
+            let error = match self.run_script(&as_ec2_user("docker info")).await {
+                Ok(invocation) if invocation.response_code == 0 => return Ok(()),
+                Ok(invocation) => SSMClientError::RemoteCommandFailed(format!(
+                    "docker info failed: {}",
+                    invocation.standard_error_content.trim()
+                )),
+                Err(err) => err,
+            };
+
+            match backoff.next_delay() {
+                Some(delay) => sleep(delay),
+                None => return Err(SSMClientError::NotReady(format!("{:?}", error)).into()),
+            }
+        }
+    }
+
+    fn connect_command(&self) -> String {
+        format!(
+            "aws ssm start-session --target {} --region {} --profile {}",
+            self.instance_id,
+            self.region.name(),
+            self.profile
+        )
+    }
+}
+
+impl SSMClient {
+    pub fn new(aws: &AwsClientFactory, instance_id: String, bucket: String) -> SSMClient {
+        SSMClient::new_with_clients(
+            aws.ssm(),
+            aws.s3(),
+            aws.get_region(),
+            aws.get_profile(),
+            instance_id,
+            bucket,
+        )
+    }
+
+    pub fn new_with_clients(
+        ssm: SsmClient,
+        s3: S3Client,
+        region: Region,
+        profile: String,
+        instance_id: String,
+        bucket: String,
+    ) -> SSMClient {
+        SSMClient {
+            ssm,
+            s3,
+            region,
+            profile,
+            instance_id,
+            bucket,
+            transfer_count: AtomicUsize::new(0),
+        }
+    }
+
+    /// SSM truncates command output so the full log is passed back through the bucket
+    async fn run_logged(
+        &self,
+        command: &str,
+        on_line: &mut OnLine<'_>,
+    ) -> Result<CommandOutput, SSMClientError> {
+        let key = self.transfer_key();
+        let script = format!(
+            "set -o pipefail\nlog=$(mktemp)\n{} > \"$log\" 2>&1\nstatus=$?\n{}\nrm -f \"$log\"\nexit $status",
+            command,
+            self.upload_command("\"$log\"", &key),
+        );
+
+        let invocation = self.run_script(&script).await?;
+        let log = self.get_object(&key).await;
+        self.delete_object(&key).await?;
+        let log = log?;
+
+        let mut tail = OutputTail::new(OUTPUT_TAIL_LINES);
+        for line in String::from_utf8_lossy(&log).lines() {
+            on_line(OutputStream::Stdout, line);
+            tail.push(line.to_owned());
+        }
+
+        Ok(CommandOutput {
+            exit_status: invocation.response_code,
+            tail: tail.into_lines(),
+        })
+    }
+
+    /// Run a script as root and wait for it to finish
+    async fn run_script(&self, script: &str) -> Result<Invocation, SSMClientError> {
+        let mut parameters = HashMap::new();
+        parameters.insert("commands".to_owned(), vec![script.to_owned()]);
+        parameters.insert(
+            "executionTimeout".to_owned(),
+            vec![EXECUTION_TIMEOUT_SECS.to_string()],
+        );
+
+        let command_id = self
+            .ssm
+            .send_command(SendCommandRequest {
+                instance_ids: Some(vec![self.instance_id.clone()]),
+                document_name: "AWS-RunShellScript".to_owned(),
+                comment: Some("container-builder".to_owned()),
+                parameters: Some(parameters),
+                ..SendCommandRequest::default()
+            })
+            .await
+            .map_err(|err| SSMClientError::RequestFailed(format!("SendCommand: {:?}", err)))?
+            .command
+            .and_then(|command| command.command_id)
+            .ok_or_else(|| {
+                SSMClientError::RequestFailed("SendCommand returned no command ID".to_owned())
+            })?;
+
+        let mut backoff = Backoff::new(
+            Duration::from_secs(1),
+            Duration::from_secs(5),
+            Duration::from_secs(EXECUTION_TIMEOUT_SECS + 5 * 60),
+        );
+        loop {
+            let response = self
+                .ssm
+                .get_command_invocation(GetCommandInvocationRequest {
+                    command_id: command_id.clone(),
+                    instance_id: self.instance_id.clone(),
+                    plugin_name: None,
+                })
+                .await;
+
+            match response {
+                Ok(invocation) => match invocation.status.as_deref() {
+                    Some("Success") | Some("Failed") => {
+                        return Ok(Invocation {
+                            response_code: invocation.response_code.unwrap_or_default() as i32,
+                            standard_error_content: invocation
+                                .standard_error_content
+                                .unwrap_or_default(),
+                        })
+                    }
+                    Some("Cancelled") | Some("TimedOut") => {
+                        return Err(SSMClientError::RemoteCommandFailed(format!(
+                            "Command {}",
+                            invocation.status.unwrap_or_default()
+                        )))
+                    }
+                    _ => {}
+                },
+                // The invocation can take a moment to appear after the command is sent
+                Err(RusotoError::Service(GetCommandInvocationError::InvocationDoesNotExist(_))) => {
+                }
+                Err(err) => {
+                    return Err(SSMClientError::RequestFailed(format!(
+                        "GetCommandInvocation: {:?}",
+                        err
+                    )))
+                }
+            }
+
+            match backoff.next_delay() {
+                Some(delay) => sleep(delay),
+                None => return Err(SSMClientError::TimedOut(command_id)),
+            }
+        }
+    }
+
+    async fn put_object(&self, key: &str, data: Vec<u8>) -> Result<(), SSMClientError> {
+        self.s3
+            .put_object(PutObjectRequest {
+                bucket: self.bucket.clone(),
+                key: key.to_owned(),
+                body: Some(data.into()),
+                server_side_encryption: Some(STAGING_ENCRYPTION.to_owned()),
+                ..PutObjectRequest::default()
+            })
+            .await
+            .map(|_| ())
+            .map_err(|err| SSMClientError::RequestFailed(format!("PUT {}: {:?}", key, err)))
+    }
+
+    async fn get_object(&self, key: &str) -> Result<Vec<u8>, SSMClientError> {
+        let output = self
+            .s3
+            .get_object(GetObjectRequest {
+                bucket: self.bucket.clone(),
+                key: key.to_owned(),
+                ..GetObjectRequest::default()
+            })
+            .await
+            .map_err(|err| SSMClientError::RequestFailed(format!("GET {}: {:?}", key, err)))?;
+
+        match output.body {
+            Some(body) => body
+                .map_ok(|bytes| bytes.to_vec())
+                .try_concat()
+                .await
+                .map_err(|err| SSMClientError::RequestFailed(format!("GET {}: {}", key, err))),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    async fn delete_object(&self, key: &str) -> Result<(), SSMClientError> {
+        self.s3
+            .delete_object(DeleteObjectRequest {
+                bucket: self.bucket.clone(),
+                key: key.to_owned(),
+                ..DeleteObjectRequest::default()
+            })
+            .await
+            .map(|_| ())
+            .map_err(|err| SSMClientError::RequestFailed(format!("DELETE {}: {:?}", key, err)))
+    }
+
+    fn transfer_key(&self) -> String {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis())
+            .unwrap_or_default();
+        let count = self.transfer_count.fetch_add(1, Ordering::SeqCst);
+
+        format!("transfers/{}-{}-{}", timestamp, std::process::id(), count)
+    }
+
+    fn upload_command(&self, source: &str, key: &str) -> String {
+        format!(
+            "aws s3 cp --quiet --sse {} --region {} {} s3://{}/{}",
+            STAGING_ENCRYPTION,
+            self.region.name(),
+            source,
+            self.bucket,
+            key
+        )
+    }
+
+    fn remove_command(&self, key: &str) -> String {
+        format!(
+            "aws s3 rm --quiet --region {} s3://{}/{}",
+            self.region.name(),
+            self.bucket,
+            key
+        )
+    }
+
+    fn download_command(&self, key: &str, destination: &str) -> String {
+        format!(
+            "aws s3 cp --quiet --region {} s3://{}/{} {}",
+            self.region.name(),
+            self.bucket,
+            key,
+            destination
+        )
+    }
+}
+
+/// Run a command as ec2-user from its home directory, as it would be over SSH
+fn as_ec2_user(command: &str) -> String {
+    format!("runuser -l ec2-user -c {}", shell_quote(command))
+}
+
+#[cfg(test)]
+fn create_test_client<S, B>(ssm_responses: S, s3_responses: B) -> SSMClient
+where
+    S: IntoIterator<Item = rusoto_mock::MockRequestDispatcher>,
+    S::IntoIter: Send + Sync + 'static,
+    B: IntoIterator<Item = rusoto_mock::MockRequestDispatcher>,
+    B::IntoIter: Send + Sync + 'static,
+{
+    SSMClient::new_with_clients(
+        SsmClient::new_with(
+            rusoto_mock::MultipleMockRequestDispatcher::new(ssm_responses),
+            rusoto_mock::MockCredentialsProvider,
+            Region::EuWest1,
+        ),
+        S3Client::new_with(
+            rusoto_mock::MultipleMockRequestDispatcher::new(s3_responses),
+            rusoto_mock::MockCredentialsProvider,
+            Region::EuWest1,
+        ),
+        Region::EuWest1,
+        "default".to_owned(),
+        "i-0123456789".to_owned(),
+        "staging-bucket".to_owned(),
+    )
+}
+
+#[cfg(test)]
+fn ssm_response(action: &'static str, body: &str) -> rusoto_mock::MockRequestDispatcher {
+    rusoto_mock::MockRequestDispatcher::with_status(200)
+        .with_body(body)
+        .with_request_checker(move |request| {
+            assert_eq!(
+                request.headers()["x-amz-target"],
+                vec![format!("AmazonSSM.{}", action).into_bytes()]
+            );
+        })
+}
+
+#[cfg(test)]
+fn s3_response(method: &'static str, body: &str) -> rusoto_mock::MockRequestDispatcher {
+    rusoto_mock::MockRequestDispatcher::with_status(200)
+        .with_body(body)
+        .with_request_checker(move |request| {
+            assert_eq!(request.method(), method);
+            assert!(request.path().starts_with("/staging-bucket/transfers/"));
+        })
+}
+
+#[tokio::test]
+async fn run_command_returns_the_log_and_exit_status() {
+    let client = create_test_client(
+        vec![
+            ssm_response("SendCommand", r#"{"Command": {"CommandId": "command-1"}}"#),
+            rusoto_mock::MockRequestDispatcher::with_status(400)
+                .with_body(r#"{"__type": "InvocationDoesNotExist", "message": "pending"}"#),
+            ssm_response(
+                "GetCommandInvocation",
+                r#"{"Status": "Failed", "ResponseCode": 2}"#,
+            ),
+        ],
+        vec![
+            s3_response("GET", "first\nsecond\n"),
+            s3_response("DELETE", ""),
+        ],
+    );
+
+    let mut lines = vec![];
+    let output = client
+        .run_command_streamed("bash script.sh", &mut |_, line| lines.push(line.to_owned()))
+        .await
+        .unwrap();
+
+    assert_eq!(output.exit_status, 2);
+    assert_eq!(lines, vec!["first".to_owned(), "second".to_owned()]);
+    assert_eq!(output.tail, lines);
+}
+
+#[tokio::test]
+async fn send_stream_stages_the_data_in_s3() {
+    let client = create_test_client(
+        vec![
+            ssm_response("SendCommand", r#"{"Command": {"CommandId": "command-1"}}"#),
+            ssm_response(
+                "GetCommandInvocation",
+                r#"{"Status": "Success", "ResponseCode": 0}"#,
+            ),
+        ],
+        vec![
+            s3_response("PUT", "").with_request_checker(|request| {
+                assert_eq!(request.method(), "PUT");
+                assert!(request.payload.is_some());
+                assert_eq!(
+                    request.headers()["x-amz-server-side-encryption"],
+                    vec![b"aws:kms".to_vec()]
+                );
+            }),
+            s3_response("DELETE", ""),
+        ],
+    );
+
+    let result = client
+        .send_stream("tar -xzf -", &mut |writer| writer.write_all(b"data"))
+        .await;
+
+    assert!(result.is_ok());
+}
+
+#[tokio::test]
+async fn read_file_returns_none_when_the_file_is_missing() {
+    let client = create_test_client(
+        vec![
+            ssm_response("SendCommand", r#"{"Command": {"CommandId": "command-1"}}"#),
+            ssm_response(
+                "GetCommandInvocation",
+                r#"{"Status": "Failed", "ResponseCode": 3}"#,
+            ),
+        ],
+        vec![],
+    );
+
+    assert!(client.read_file("missing.txt").await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn cancelled_commands_are_errors() {
+    let client = create_test_client(
+        vec![
+            ssm_response("SendCommand", r#"{"Command": {"CommandId": "command-1"}}"#),
+            ssm_response("GetCommandInvocation", r#"{"Status": "Cancelled"}"#),
+        ],
+        vec![],
+    );
+
+    assert!(client.exists("~/builds").await.is_err());
+}
+
+#[tokio::test]
+async fn input_is_removed_from_the_bucket_before_the_command_runs() {
+    let client = create_test_client(
+        vec![
+            ssm_response("SendCommand", r#"{"Command": {"CommandId": "command-1"}}"#)
+                .with_request_checker(|request| {
+                    let body = String::from_utf8_lossy(match &request.payload {
+                        Some(rusoto_core::signature::SignedRequestPayload::Buffer(body)) => body,
+                        _ => panic!("SendCommand has no body"),
+                    })
+                    .into_owned();
+                    let removed_at = body.find("aws s3 rm").unwrap();
+                    let run_at = body.find("docker login").unwrap();
+                    assert!(removed_at < run_at);
+                }),
+            ssm_response(
+                "GetCommandInvocation",
+                r#"{"Status": "Success", "ResponseCode": 0}"#,
+            ),
+        ],
+        vec![
+            s3_response("PUT", ""),
+            s3_response("GET", "Login Succeeded\n"),
+            s3_response("DELETE", ""),
+            s3_response("DELETE", ""),
+        ],
+    );
+
+    let output = client
+        .run_command_with_input("docker login --password-stdin", b"secret", &mut |_, _| {})
+        .await
+        .unwrap();
+
+    assert_eq!(output.exit_status, 0);
+}
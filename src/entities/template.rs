@@ -0,0 +1,72 @@
+use std::path::Path;
+
+/// The CloudFormation templates compiled into the binary so it works from any directory
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Template {
+    Instance,
+    Role,
+}
+
+const TEMPLATES: [Template; 2] = [Template::Instance, Template::Role];
+
+impl Template {
+    pub fn from_name(name: &str) -> Option<Template> {
+        TEMPLATES
+            .iter()
+            .find(|template| template.name() == name)
+            .copied()
+    }
+
+    pub fn names() -> Vec<&'static str> {
+        TEMPLATES.iter().map(|template| template.name()).collect()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Template::Instance => "instance",
+            Template::Role => "role",
+        }
+    }
+
+    pub fn embedded(self) -> &'static str {
+        match self {
+            Template::Instance => include_str!("../../resources/instance-cfn.yml"),
+            Template::Role => include_str!("../../resources/role-cfn.yml"),
+        }
+    }
+
+    /// Use the template at `override_path` if one is given, otherwise the embedded version
+    pub fn load(self, override_path: Option<&Path>) -> Result<String, String> {
+        match override_path {
+            Some(path) => std::fs::read_to_string(path)
+                .map_err(|err| format!("Failed to read template {}: {}", path.display(), err)),
+            None => Ok(self.embedded().to_owned()),
+        }
+    }
+}
+
+#[test]
+fn templates_can_be_found_by_name() {
+    assert_eq!(Template::from_name("instance"), Some(Template::Instance));
+    assert_eq!(Template::from_name("role"), Some(Template::Role));
+    assert_eq!(Template::from_name("instance-cfn.yml"), None);
+}
+
+#[test]
+fn embedded_templates_are_the_resource_files() {
+    assert!(Template::Instance.embedded().contains("AWS::EC2::Instance"));
+    assert!(Template::Role
+        .embedded()
+        .contains("ContainerBuilderPushRole"));
+}
+
+#[test]
+fn override_path_replaces_the_embedded_template() {
+    assert!(Template::Role
+        .load(Some(Path::new("resources/role-cfn.yml")))
+        .unwrap()
+        .contains("ContainerBuilderPushRole"));
+    assert!(Template::Role
+        .load(Some(Path::new("does/not/exist.yml")))
+        .is_err());
+}
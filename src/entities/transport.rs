@@ -0,0 +1,207 @@
+use crate::{
+    AwsClientFactory, CfnClient, Config, HostKeys, SSHClient, SSHClientError, SSMClient,
+    SSMClientError,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+// Number of output lines kept so failures can be reported without reading a remote log
+pub(crate) const OUTPUT_TAIL_LINES: usize = 50;
+
+/// How the builder instance is reached
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportKind {
+    /// Connect straight to the instance over SSH
+    #[default]
+    Ssh,
+    /// Run commands through SSM and stage files in S3, for accounts without inbound SSH
+    Ssm,
+}
+
+pub fn transport_parser(maybe_transport: &str) -> Result<TransportKind, String> {
+    match maybe_transport {
+        "ssh" => Ok(TransportKind::Ssh),
+        "ssm" => Ok(TransportKind::Ssm),
+        _ => Err(format!(
+            "Transport ({}) is not valid. Use ssh or ssm",
+            maybe_transport
+        )),
+    }
+}
+
+pub fn transport_validator(maybe_transport: String) -> Result<(), String> {
+    transport_parser(&maybe_transport).map(|_| ())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Debug)]
+pub struct CommandOutput {
+    pub exit_status: i32,
+    pub tail: Vec<String>,
+}
+
+/// Writes the data sent to a remote command
+pub type WriteData<'a> = dyn FnMut(&mut dyn Write) -> std::io::Result<()> + Send + 'a;
+
+/// Receives each line of output from a remote command
+pub type OnLine<'a> = dyn FnMut(OutputStream, &str) + Send + 'a;
+
+#[derive(Debug)]
+pub enum TransportError {
+    SSH(SSHClientError),
+    SSM(SSMClientError),
+    SetupFailed(String),
+    #[cfg(test)]
+    Local(String),
+}
+
+impl From<SSHClientError> for TransportError {
+    fn from(error: SSHClientError) -> Self {
+        TransportError::SSH(error)
+    }
+}
+
+impl From<SSMClientError> for TransportError {
+    fn from(error: SSMClientError) -> Self {
+        TransportError::SSM(error)
+    }
+}
+
+/// Runs commands and moves files to and from the builder instance. Remote paths are
+/// relative to the home directory of ec2-user.
+#[async_trait::async_trait]
+pub trait BuilderTransport: Send + Sync {
+    /// Write `data` to a file in an existing directory
+    async fn send_data(
+        &self,
+        data: &[u8],
+        remote_filename: &str,
+        mode: i32,
+    ) -> Result<(), TransportError>;
+
+    /// Read a file, returning `None` if it doesn't exist
+    async fn read_file(&self, remote_filename: &str) -> Result<Option<Vec<u8>>, TransportError>;
+
+    /// Check whether a file or directory exists
+    async fn exists(&self, remote_path: &str) -> Result<bool, TransportError>;
+
+    /// Run a command with the data written by `write_data` as its stdin
+    async fn send_stream(
+        &self,
+        remote_command: &str,
+        write_data: &mut WriteData<'_>,
+    ) -> Result<(), TransportError>;
+
+    /// Run a command, passing each line of its output to `on_line`. The last lines of
+    /// combined output are returned along with the exit status.
+    async fn run_command_streamed(
+        &self,
+        command: &str,
+        on_line: &mut OnLine<'_>,
+    ) -> Result<CommandOutput, TransportError>;
+
+    /// Like `run_command_streamed` with `input` as the command's stdin. The input is never
+    /// written to disk on the instance, so it can hold secrets. The SSM transport stages it
+    /// in the staging bucket, encrypted with KMS, and deletes it before the command starts.
+    async fn run_command_with_input(
+        &self,
+        command: &str,
+        input: &[u8],
+        on_line: &mut OnLine<'_>,
+    ) -> Result<CommandOutput, TransportError>;
+
+    /// Wait until commands can be run and docker is up
+    async fn wait_until_ready(&self, timeout: Duration) -> Result<(), TransportError>;
+
+    /// A shell command which opens an interactive session on the instance
+    fn connect_command(&self) -> String;
+}
+
+/// Create the transport selected in the config
+pub async fn create_transport(
+    config: &Config,
+    working_dir: &Path,
+) -> Result<Box<dyn BuilderTransport>, TransportError> {
+    match config.get_transport() {
+        TransportKind::Ssh => {
+            let client = SSHClient::new(
+                config.get_instance_ip(),
+                working_dir.join("ContainerBuilderKey.pem"),
+                HostKeys::new(working_dir.join("known_hosts")),
+            );
+
+            Ok(Box::new(match config.get_ssh_connect_timeout() {
+                Some(timeout) => client.with_connect_timeout(timeout),
+                None => client,
+            }))
+        }
+        TransportKind::Ssm => {
+            let aws = AwsClientFactory::new(config.get_base_profile(), config.get_region());
+            let cfn_client = CfnClient::new(&aws);
+
+            let instance_id = cfn_client.get_instance_id().await.ok_or_else(|| {
+                TransportError::SetupFailed("Failed to find the instance".to_owned())
+            })?;
+            let bucket = cfn_client.get_staging_bucket().await.ok_or_else(|| {
+                TransportError::SetupFailed(
+                    "Failed to find the staging bucket. Run reconfigure to add it to the stack"
+                        .to_owned(),
+                )
+            })?;
+
+            Ok(Box::new(SSMClient::new(&aws, instance_id, bucket)))
+        }
+    }
+}
+
+/// Keeps the most recent lines of output
+pub(crate) struct OutputTail {
+    lines: VecDeque<String>,
+    capacity: usize,
+}
+
+impl OutputTail {
+    pub(crate) fn new(capacity: usize) -> OutputTail {
+        OutputTail {
+            lines: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub(crate) fn push(&mut self, line: String) {
+        if self.lines.len() == self.capacity {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+    }
+
+    pub(crate) fn into_lines(self) -> Vec<String> {
+        self.lines.into_iter().collect()
+    }
+}
+
+#[test]
+fn output_tail_keeps_the_latest_lines() {
+    let mut tail = OutputTail::new(2);
+    tail.push("a".to_owned());
+    tail.push("b".to_owned());
+    tail.push("c".to_owned());
+
+    assert_eq!(tail.into_lines(), vec!["b".to_owned(), "c".to_owned()]);
+}
+
+#[test]
+fn transport_parser_accepts_ssh_and_ssm() {
+    assert_eq!(transport_parser("ssh"), Ok(TransportKind::Ssh));
+    assert_eq!(transport_parser("ssm"), Ok(TransportKind::Ssm));
+    assert!(transport_parser("telnet").is_err());
+}
@@ -17,6 +17,12 @@ async fn main() {
     let add_account_subcommand = cli::AddAccountCommand::new();
     let start_subcommand = cli::StartCommand::new();
     let stop_subcommand = cli::StopCommand::new();
+    let idle_shutdown_subcommand = cli::IdleShutdownCommand::new();
+    let status_subcommand = cli::StatusCommand::new();
+    let template_subcommand = cli::TemplateCommand::new();
+    let reconfigure_subcommand = cli::ReconfigureCommand::new();
+    let allow_ip_subcommand = cli::AllowIpCommand::new();
+    let cache_subcommand = cli::CacheCommand::new();
 
     let matches = App::new("builder")
         .name("AWS container builder")
@@ -30,6 +36,12 @@ async fn main() {
         .subcommand(add_account_subcommand.subcommand())
         .subcommand(start_subcommand.subcommand())
         .subcommand(stop_subcommand.subcommand())
+        .subcommand(idle_shutdown_subcommand.subcommand())
+        .subcommand(status_subcommand.subcommand())
+        .subcommand(template_subcommand.subcommand())
+        .subcommand(reconfigure_subcommand.subcommand())
+        .subcommand(allow_ip_subcommand.subcommand())
+        .subcommand(cache_subcommand.subcommand())
         .get_matches();
 
     // Handle subcommands
@@ -40,4 +52,10 @@ async fn main() {
     cli::run_if_called(&add_account_subcommand, &matches).await;
     cli::run_if_called(&start_subcommand, &matches).await;
     cli::run_if_called(&stop_subcommand, &matches).await;
+    cli::run_if_called(&idle_shutdown_subcommand, &matches).await;
+    cli::run_if_called(&status_subcommand, &matches).await;
+    cli::run_if_called(&template_subcommand, &matches).await;
+    cli::run_if_called(&reconfigure_subcommand, &matches).await;
+    cli::run_if_called(&allow_ip_subcommand, &matches).await;
+    cli::run_if_called(&cache_subcommand, &matches).await;
 }
@@ -1,5 +1,6 @@
 use crate::get_current_account_no;
-use crate::{CfnClient, Config, SimpleParameter, Tag};
+use crate::{AwsClientFactory, CfnClient, Config, SimpleParameter, Tag, Template};
+use std::path::Path;
 
 #[derive(Debug)]
 pub enum AddAccountError {
@@ -13,6 +14,7 @@ pub enum AddAccountError {
 pub async fn run_add_account(
     new_account_profile: String,
     tags: Vec<Tag>,
+    template_path: Option<&Path>,
 ) -> Result<(), AddAccountError> {
     let home_dir = dirs::home_dir().expect("Could not find home directory");
     let working_dir = home_dir.join(".cbuilder");
@@ -21,14 +23,18 @@ pub async fn run_add_account(
     let mut config = Config::read_from_file(&working_dir.join("properties.yml"))
         .ok_or(AddAccountError::FailedToLoadConfig)?;
 
+    let base_aws = AwsClientFactory::new(config.get_base_profile(), config.get_region());
+    let new_account_aws =
+        AwsClientFactory::with_explicit_profile(new_account_profile.clone(), config.get_region());
+
     // Find the base account id
-    let base_account_id = get_current_account_no(config.get_base_profile())
+    let base_account_id = get_current_account_no(&base_aws)
         .await
-        .map_err(|err| AddAccountError::FailedGetCurrentAccountNo(err))?;
+        .map_err(AddAccountError::FailedGetCurrentAccountNo)?;
 
-    let new_account_id = get_current_account_no(new_account_profile.clone())
+    let new_account_id = get_current_account_no(&new_account_aws)
         .await
-        .map_err(|err| AddAccountError::FailedGetCurrentAccountNo(err))?;
+        .map_err(AddAccountError::FailedGetCurrentAccountNo)?;
 
     // Work out the new AccountRoles parameter based off the account and the base account
     let mut current_accounts = config.get_account_numbers();
@@ -40,7 +46,7 @@ pub async fn run_add_account(
         .collect::<Vec<String>>()
         .join(",");
 
-    let cfn_client = CfnClient::new(config.get_base_profile());
+    let cfn_client = CfnClient::new(&base_aws);
 
     // Redeploy the base cloudformation stack with extra permissions
     let did_update_stack = cfn_client.update_stack(accounts_parameters_value).await;
@@ -50,11 +56,12 @@ pub async fn run_add_account(
     }
 
     // Deploy the role stack in the new account
-    let new_stacktemplate = std::fs::read_to_string("resources/role-cfn.yml")
+    let new_stacktemplate = Template::Role
+        .load(template_path)
         .map_err(|_| AddAccountError::FailedCreateNewStack)?;
 
     // Create cloudformation client
-    let new_cfn_client = CfnClient::new(new_account_profile.clone());
+    let new_cfn_client = CfnClient::new(&new_account_aws);
     new_cfn_client
         .deploy_stack(
             "container-builder-role".to_owned(),
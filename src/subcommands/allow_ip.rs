@@ -0,0 +1,145 @@
+use crate::{get_public_ip, AwsClientFactory, CfnClient, Config, EC2Client};
+
+#[derive(Debug)]
+pub enum AllowIpError {
+    CouldNotFindConfig,
+    InstanceNotFound,
+    PublicIpLookupFailed(String),
+    UpdateIngressFailed(String),
+    FailedSaveConfig,
+}
+
+/// Allow SSH from the given CIDRs, or the caller's public IP if there are none. With `add`
+/// the CIDRs are allowed alongside the existing ones, otherwise they replace them.
+pub async fn run_allow_ip(cidrs: Vec<String>, add: bool) -> Result<Vec<String>, AllowIpError> {
+    let home_dir = dirs::home_dir().expect("Could not find home directory");
+    let props_file_path = home_dir.join(".cbuilder").join("properties.yml");
+    let mut config =
+        Config::read_from_file(&props_file_path).ok_or(AllowIpError::CouldNotFindConfig)?;
+
+    let mut allowed_cidrs = resolve_allowed_cidrs(cidrs)
+        .await
+        .map_err(AllowIpError::PublicIpLookupFailed)?;
+    if add {
+        let mut existing_cidrs = config.get_allowed_cidrs();
+        existing_cidrs.retain(|cidr| !allowed_cidrs.contains(cidr));
+        existing_cidrs.append(&mut allowed_cidrs);
+        allowed_cidrs = existing_cidrs;
+    }
+
+    let aws = AwsClientFactory::new(config.get_base_profile(), config.get_region());
+    let instance_id = CfnClient::new(&aws)
+        .get_instance_id()
+        .await
+        .ok_or(AllowIpError::InstanceNotFound)?;
+    sync_ssh_ingress(&EC2Client::new(&aws), instance_id, &allowed_cidrs)
+        .await
+        .map_err(AllowIpError::UpdateIngressFailed)?;
+
+    config.set_allowed_cidrs(allowed_cidrs.clone());
+    config
+        .write_to_file(&props_file_path)
+        .map_err(|_| AllowIpError::FailedSaveConfig)?;
+
+    Ok(allowed_cidrs)
+}
+
+/// Use the CIDRs given on the command line, defaulting to just the caller's public IP
+pub async fn resolve_allowed_cidrs(cidrs: Vec<String>) -> Result<Vec<String>, String> {
+    if !cidrs.is_empty() {
+        return Ok(cidrs);
+    }
+
+    let ip = get_public_ip().await?;
+    Ok(vec![format!("{}/32", ip)])
+}
+
+/// Make the SSH ingress rules of the instance's security group match `allowed_cidrs` exactly
+pub async fn sync_ssh_ingress(
+    ec2_client: &EC2Client,
+    instance_id: String,
+    allowed_cidrs: &[String],
+) -> Result<(), String> {
+    let group_id = ec2_client
+        .get_instance_security_group(instance_id)
+        .await
+        .ok_or_else(|| "Failed to find the instance security group".to_owned())?;
+    let current_cidrs = ec2_client
+        .get_ssh_ingress(group_id.clone())
+        .await
+        .ok_or_else(|| "Failed to describe the instance security group".to_owned())?;
+
+    let (to_add, to_remove) = plan_ingress_changes(&current_cidrs, allowed_cidrs);
+
+    // Add first so a failure part way through doesn't lock everyone out
+    if !to_add.is_empty()
+        && !ec2_client
+            .authorize_ssh_ingress(group_id.clone(), &to_add)
+            .await
+    {
+        return Err(format!("Failed to allow SSH from {}", to_add.join(", ")));
+    }
+    if !to_remove.is_empty() && !ec2_client.revoke_ssh_ingress(group_id, &to_remove).await {
+        return Err(format!(
+            "Failed to remove SSH from {}",
+            to_remove.join(", ")
+        ));
+    }
+
+    Ok(())
+}
+
+/// Allow SSH from `cidrs` without removing any existing rules, returning every range which
+/// is then allowed
+pub async fn add_ssh_ingress(
+    ec2_client: &EC2Client,
+    instance_id: String,
+    cidrs: &[String],
+) -> Result<Vec<String>, String> {
+    let group_id = ec2_client
+        .get_instance_security_group(instance_id)
+        .await
+        .ok_or_else(|| "Failed to find the instance security group".to_owned())?;
+    let mut current_cidrs = ec2_client
+        .get_ssh_ingress(group_id.clone())
+        .await
+        .ok_or_else(|| "Failed to describe the instance security group".to_owned())?;
+
+    let (mut to_add, _) = plan_ingress_changes(&current_cidrs, cidrs);
+    if !to_add.is_empty() && !ec2_client.authorize_ssh_ingress(group_id, &to_add).await {
+        return Err(format!("Failed to allow SSH from {}", to_add.join(", ")));
+    }
+
+    current_cidrs.append(&mut to_add);
+    Ok(current_cidrs)
+}
+
+fn plan_ingress_changes(current: &[String], desired: &[String]) -> (Vec<String>, Vec<String>) {
+    let to_add = desired
+        .iter()
+        .filter(|cidr| !current.contains(cidr))
+        .cloned()
+        .collect();
+    let to_remove = current
+        .iter()
+        .filter(|cidr| !desired.contains(cidr))
+        .cloned()
+        .collect();
+
+    (to_add, to_remove)
+}
+
+#[test]
+fn ingress_changes_only_touch_differences() {
+    let current = vec!["0.0.0.0/0".to_owned(), "10.0.0.0/8".to_owned()];
+    let desired = vec!["10.0.0.0/8".to_owned(), "203.0.113.7/32".to_owned()];
+
+    assert_eq!(
+        plan_ingress_changes(&current, &desired),
+        (
+            vec!["203.0.113.7/32".to_owned()],
+            vec!["0.0.0.0/0".to_owned()]
+        )
+    );
+    assert_eq!(plan_ingress_changes(&desired, &desired), (vec![], vec![]));
+}
@@ -1,13 +1,20 @@
 use chrono::prelude::*;
 use core::str::FromStr;
+use rusoto_core::Region;
 use rusoto_ec2::Image;
-use std::fs::{create_dir, read_to_string, File};
+use std::fs::{create_dir, File};
 use std::io::prelude::*;
 use std::ops::Fn;
 use std::os::unix::fs::PermissionsExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::{thread::sleep, time::Duration};
 
-use crate::{get_current_account_no, CfnClient, Config, EC2Client, SimpleParameter, Tag};
+use super::allow_ip::{resolve_allowed_cidrs, sync_ssh_ingress};
+use crate::{
+    get_current_account_no, parse_console_host_keys, Architecture, AwsClientFactory, Backoff,
+    CfnClient, Config, EC2Client, HostKeyError, HostKeys, SimpleParameter, Tag, Template,
+    TransportKind,
+};
 
 #[derive(Debug)]
 pub enum BootstrapErrors {
@@ -19,17 +26,83 @@ pub enum BootstrapErrors {
     FailedWriteConfig,
     FailedSetKeyPermissions,
     FailedGetCurrentAccountId(String),
+    FailedDescribeInstanceType,
+    InstanceTypeNotSupported(String),
+    FailedAllowIngress(String),
+    FailedPinHostKeys(String),
 }
 
-pub async fn run_bootstrap(profile: String, tags: Vec<Tag>) -> Result<(), BootstrapErrors> {
+/// The size and shape of the builder instance
+pub struct InstanceSettings {
+    pub instance_type: String,
+    pub volume_size: u32,
+    pub volume_type: String,
+    pub architecture: Architecture,
+    /// Ranges allowed to SSH to the instance. Empty means the caller's public IP.
+    pub allowed_cidrs: Vec<String>,
+    pub transport: TransportKind,
+}
+
+impl InstanceSettings {
+    fn to_parameters(&self) -> Vec<SimpleParameter> {
+        vec![
+            SimpleParameter::new("InstanceType".to_owned(), self.instance_type.clone()),
+            SimpleParameter::new("VolumeSize".to_owned(), self.volume_size.to_string()),
+            SimpleParameter::new("VolumeType".to_owned(), self.volume_type.clone()),
+        ]
+    }
+}
+
+/// `explicit_profile` is false when the profile came from `AWS_PROFILE`, in which case
+/// credentials in the environment take precedence as they do for the AWS CLI
+pub async fn run_bootstrap(
+    profile: String,
+    explicit_profile: bool,
+    region: Region,
+    tags: Vec<Tag>,
+    template_path: Option<&Path>,
+    settings: InstanceSettings,
+) -> Result<(), BootstrapErrors> {
     let home_dir = dirs::home_dir().expect("Could not find home directory");
     let working_dir = home_dir.join(".cbuilder");
 
     ensure_working_dir_exists(working_dir.clone(), create_dir)
-        .map_err(|err| BootstrapErrors::FailedCreateWorkingDir(err))?;
+        .map_err(BootstrapErrors::FailedCreateWorkingDir)?;
+
+    let aws = if explicit_profile {
+        AwsClientFactory::with_explicit_profile(profile.clone(), region.clone())
+    } else {
+        AwsClientFactory::new(profile.clone(), region.clone())
+    };
+
+    let my_ec2 = EC2Client::new(&aws);
+
+    // Nothing needs to reach the instance when commands are sent through SSM
+    let allowed_cidrs = match settings.transport {
+        TransportKind::Ssh => resolve_allowed_cidrs(settings.allowed_cidrs.clone())
+            .await
+            .map_err(BootstrapErrors::FailedAllowIngress)?,
+        TransportKind::Ssm => vec![],
+    };
+
+    // Check the instance type before creating anything which would need cleaning up
+    let architectures = my_ec2
+        .get_instance_type_architectures(settings.instance_type.clone())
+        .await
+        .ok_or(BootstrapErrors::FailedDescribeInstanceType)?;
+    if !architectures
+        .iter()
+        .any(|arch| arch == settings.architecture.name())
+    {
+        return Err(BootstrapErrors::InstanceTypeNotSupported(format!(
+            "{} does not support {}. Supported architectures: {}",
+            settings.instance_type,
+            settings.architecture.name(),
+            architectures.join(", ")
+        )));
+    }
 
     // Create SSH Key into directory
-    let my_ec2 = EC2Client::new(profile.clone());
     let key = my_ec2
         .create_ssh_key()
         .await
@@ -40,22 +113,23 @@ pub async fn run_bootstrap(profile: String, tags: Vec<Tag>) -> Result<(), Bootst
 
     // Find the correct AWS Amazon Linux 2 AMI
     let images = my_ec2
-        .get_amazon_linux_2_ami()
+        .get_amazon_linux_2_ami(settings.architecture)
         .await
         .ok_or(BootstrapErrors::FailedFindAmi)?;
     let linux_ami = get_amazon_linux_2_ami(images).await?;
 
     // Deploy cloudformation stack
-    let cfn_client = CfnClient::new(profile.clone());
+    let cfn_client = CfnClient::new(&aws);
 
-    // Load the cloudformation template file to a string
-    let cfn_template = read_to_string("resources/instance-cfn.yml")
-        .map_err(|err| BootstrapErrors::FailedStackCreation(err.to_string()))?;
+    // Load the cloudformation template
+    let cfn_template = Template::Instance
+        .load(template_path)
+        .map_err(BootstrapErrors::FailedStackCreation)?;
 
     // Get the current account id
-    let account_id = get_current_account_no(profile.clone())
+    let account_id = get_current_account_no(&aws)
         .await
-        .map_err(|err| BootstrapErrors::FailedGetCurrentAccountId(err))?;
+        .map_err(BootstrapErrors::FailedGetCurrentAccountId)?;
     let role = format!("arn:aws:iam::{}:role/ContainerBuilderPushRole", account_id);
 
     // Deploy the cloudformation template
@@ -63,11 +137,15 @@ pub async fn run_bootstrap(profile: String, tags: Vec<Tag>) -> Result<(), Bootst
         .deploy_stack(
             "container-builder".to_owned(),
             cfn_template,
-            &vec![
-                SimpleParameter::new("AmiId".to_owned(), linux_ami),
-                SimpleParameter::new("SSHKeyName".to_owned(), "ContainerBuilderKey".to_owned()),
-                SimpleParameter::new("AccountRoles".to_owned(), role),
-            ],
+            &[
+                vec![
+                    SimpleParameter::new("AmiId".to_owned(), linux_ami),
+                    SimpleParameter::new("SSHKeyName".to_owned(), "ContainerBuilderKey".to_owned()),
+                    SimpleParameter::new("AccountRoles".to_owned(), role),
+                ],
+                settings.to_parameters(),
+            ]
+            .concat(),
             &tags,
             7 * 60,
         )
@@ -79,13 +157,29 @@ pub async fn run_bootstrap(profile: String, tags: Vec<Tag>) -> Result<(), Bootst
         .await
         .ok_or(BootstrapErrors::FailedDescribeStack)?;
 
-    let ec2_client = EC2Client::new(profile.clone());
-    let instance_ip = ec2_client
-        .get_instance_ip(instance_id)
+    sync_ssh_ingress(&my_ec2, instance_id.clone(), &allowed_cidrs)
+        .await
+        .map_err(BootstrapErrors::FailedAllowIngress)?;
+
+    let instance_ip = my_ec2
+        .get_instance_ip(instance_id.clone())
         .await
         .ok_or(BootstrapErrors::FailedDescribeStack)?;
 
-    let config = Config::new(instance_ip, profile.clone());
+    if settings.transport == TransportKind::Ssh {
+        println!("Waiting for the instance to report its SSH host keys");
+        let host_keys = HostKeys::new(working_dir.join("known_hosts"));
+        let pinned = capture_host_keys(&my_ec2, instance_id, &instance_ip, &host_keys)
+            .await
+            .map_err(|err| BootstrapErrors::FailedPinHostKeys(format!("{:?}", err)))?;
+        if !pinned {
+            println!("The host keys were not in the console output yet, they will be pinned on the first connection");
+        }
+    }
+
+    let mut config = Config::new(instance_ip, profile, region);
+    config.set_allowed_cidrs(allowed_cidrs);
+    config.set_transport(settings.transport);
     config
         .write_to_file(&working_dir.join("properties.yml"))
         .map_err(|_| BootstrapErrors::FailedWriteConfig)?;
@@ -93,6 +187,38 @@ pub async fn run_bootstrap(profile: String, tags: Vec<Tag>) -> Result<(), Bootst
     return Ok(());
 }
 
+/// Pin the host keys a new instance prints to its console so even the first connection is
+/// verified. Returns false if they didn't appear in time.
+pub async fn capture_host_keys(
+    ec2_client: &EC2Client,
+    instance_id: String,
+    instance_ip: &str,
+    host_keys: &HostKeys,
+) -> Result<bool, HostKeyError> {
+    // Keys pinned for a previous instance would never match
+    host_keys.forget()?;
+
+    let mut backoff = Backoff::new(
+        Duration::from_secs(10),
+        Duration::from_secs(30),
+        Duration::from_secs(5 * 60),
+    );
+    loop {
+        if let Some(output) = ec2_client.get_console_output(instance_id.clone()).await {
+            let keys = parse_console_host_keys(&output);
+            if !keys.is_empty() {
+                host_keys.pin(instance_ip, &keys)?;
+                return Ok(true);
+            }
+        }
+
+        match backoff.next_delay() {
+            Some(delay) => sleep(delay),
+            None => return Ok(false),
+        }
+    }
+}
+
 trait HasExists {
     fn exists(&self) -> bool;
 }
@@ -0,0 +1,304 @@
+use super::start::{ensure_running, StartError};
+use crate::{create_transport, BuilderTransport, Config};
+use std::fmt;
+
+// Docker keeps images & the build cache here on the instance
+const DOCKER_ROOT: &str = "/var/lib/docker";
+const KB_PER_GB: u64 = 1024 * 1024;
+// Lines of command output holding the disk space start with this
+const DISK_SPACE_MARKER: &str = "disk-space";
+// The first pass of an automatic prune keeps build cache used in the last day
+const AUTO_PRUNE_KEEP_HOURS: u32 = 24;
+// If that wasn't enough the build cache is shrunk to this size, keeping what was used last
+const AUTO_PRUNE_KEEP_GB: u64 = 10;
+
+#[derive(Debug)]
+pub enum CacheError {
+    CouldNotFindConfig,
+    InstanceNotStarted(StartError),
+    TransportFailed(String),
+    CommandFailed(String),
+    DiskSpaceNotFound,
+    FailedSaveConfig,
+}
+
+/// How much of the docker cache to remove
+pub enum PrunePolicy {
+    /// Remove build cache which hasn't been used in the given number of hours, along with
+    /// dangling images. Tagged images, such as pulled base images, are kept.
+    UnusedFor(u32),
+    /// Remove the least recently used build cache until it takes up at most this many GB
+    KeepGb(u64),
+}
+
+/// The size & free space of the disk docker stores its data on
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiskSpace {
+    pub size_kb: u64,
+    pub free_kb: u64,
+}
+
+impl fmt::Display for DiskSpace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.1} GB free of {:.1} GB",
+            self.free_kb as f64 / KB_PER_GB as f64,
+            self.size_kb as f64 / KB_PER_GB as f64
+        )
+    }
+}
+
+pub struct CacheUsage {
+    /// The output of `docker system df`
+    pub docker: Vec<String>,
+    pub disk: DiskSpace,
+}
+
+/// The disk space before & after a prune along with docker's output
+pub struct PruneReport {
+    pub output: Vec<String>,
+    pub before: DiskSpace,
+    pub after: DiskSpace,
+}
+
+/// Report how much space docker's images & build cache take up on the instance
+pub async fn cache_usage() -> Result<CacheUsage, CacheError> {
+    let transport = connect().await?;
+    let (docker, mut disk) = run_cache_command(
+        transport.as_ref(),
+        &format!("docker system df\n{}", disk_space_command()),
+    )
+    .await?;
+
+    Ok(CacheUsage {
+        docker,
+        disk: disk.pop().ok_or(CacheError::DiskSpaceNotFound)?,
+    })
+}
+
+/// Remove part of the docker cache on the instance
+pub async fn prune_cache(policy: PrunePolicy) -> Result<PruneReport, CacheError> {
+    let transport = connect().await?;
+    let command = format!(
+        "{space}\n{prune}\n{space}",
+        space = disk_space_command(),
+        prune = prune_command(&policy)
+    );
+
+    let (output, disk) = run_cache_command(transport.as_ref(), &command).await?;
+    prune_report(output, disk)
+}
+
+/// Save the free space below which `ship` prunes the cache before building, or with
+/// `None` stop pruning automatically
+pub fn set_auto_prune(min_free_gb: Option<u64>) -> Result<(), CacheError> {
+    let home_dir = dirs::home_dir().expect("Could not find home directory");
+    let props_file_path = home_dir.join(".cbuilder").join("properties.yml");
+    let mut config =
+        Config::read_from_file(&props_file_path).ok_or(CacheError::CouldNotFindConfig)?;
+
+    config.set_cache_min_free_gb(min_free_gb);
+    config
+        .write_to_file(&props_file_path)
+        .map_err(|_| CacheError::FailedSaveConfig)
+}
+
+/// Prune the cache if less than `min_free_gb` is free. Build cache unused for a day and
+/// dangling images go first, then the build cache is shrunk to `AUTO_PRUNE_KEEP_GB` if that
+/// wasn't enough. Returns `None` when there was already enough space.
+pub(super) async fn auto_prune(
+    transport: &dyn BuilderTransport,
+    min_free_gb: u64,
+) -> Result<Option<PruneReport>, CacheError> {
+    let (output, disk) =
+        run_cache_command(transport, &auto_prune_command(min_free_gb * KB_PER_GB)).await?;
+
+    // Only a pruned disk is measured twice
+    if disk.len() < 2 {
+        return Ok(None);
+    }
+    prune_report(output, disk).map(Some)
+}
+
+async fn connect() -> Result<Box<dyn BuilderTransport>, CacheError> {
+    let home_dir = dirs::home_dir().expect("Could not find home directory");
+    let working_dir = home_dir.join(".cbuilder");
+    let mut config = Config::read_from_file(&working_dir.join("properties.yml"))
+        .ok_or(CacheError::CouldNotFindConfig)?;
+
+    ensure_running(&mut config, &working_dir)
+        .await
+        .map_err(CacheError::InstanceNotStarted)?;
+
+    create_transport(&config, &working_dir)
+        .await
+        .map_err(|err| CacheError::TransportFailed(format!("{:#?}", err)))
+}
+
+/// Run `command` on the instance, separating the disk space measurements from the rest of
+/// its output
+async fn run_cache_command(
+    transport: &dyn BuilderTransport,
+    command: &str,
+) -> Result<(Vec<String>, Vec<DiskSpace>), CacheError> {
+    let mut output = vec![];
+    let mut disk = vec![];
+    let result = transport
+        .run_command_streamed(command, &mut |_, line| match parse_disk_space(line) {
+            Some(space) => disk.push(space),
+            None => output.push(line.to_owned()),
+        })
+        .await
+        .map_err(|err| CacheError::TransportFailed(format!("{:#?}", err)))?;
+
+    if result.exit_status != 0 {
+        return Err(CacheError::CommandFailed(result.tail.join("\n")));
+    }
+    Ok((output, disk))
+}
+
+fn prune_report(output: Vec<String>, disk: Vec<DiskSpace>) -> Result<PruneReport, CacheError> {
+    match (disk.first(), disk.last()) {
+        (Some(before), Some(after)) if disk.len() > 1 => Ok(PruneReport {
+            output,
+            before: before.clone(),
+            after: after.clone(),
+        }),
+        _ => Err(CacheError::DiskSpaceNotFound),
+    }
+}
+
+/// Print the size & free space in KB on a line marked with `DISK_SPACE_MARKER`
+fn disk_space_command() -> String {
+    format!(
+        "df -Pk {} | awk 'NR == 2 {{ print \"{}\", $2, $4 }}'",
+        DOCKER_ROOT, DISK_SPACE_MARKER
+    )
+}
+
+fn free_kb_command() -> String {
+    format!("df -Pk {} | awk 'NR == 2 {{ print $4 }}'", DOCKER_ROOT)
+}
+
+fn prune_command(policy: &PrunePolicy) -> String {
+    match policy {
+        PrunePolicy::UnusedFor(hours) => format!(
+            "docker builder prune --all --force --filter unused-for={}h && docker image prune --force",
+            hours
+        ),
+        PrunePolicy::KeepGb(gb) => {
+            format!("docker builder prune --all --force --keep-storage {}gb", gb)
+        }
+    }
+}
+
+fn auto_prune_command(min_free_kb: u64) -> String {
+    let mut script = Vec::new();
+    script.push(format!(
+        "if [ \"$({})\" -lt {} ]; then",
+        free_kb_command(),
+        min_free_kb
+    ));
+    script.push(format!("    {}", disk_space_command()));
+    script.push(format!(
+        "    {}",
+        prune_command(&PrunePolicy::UnusedFor(AUTO_PRUNE_KEEP_HOURS))
+    ));
+    script.push(format!(
+        "    if [ \"$({})\" -lt {} ]; then",
+        free_kb_command(),
+        min_free_kb
+    ));
+    script.push(format!(
+        "        {}",
+        prune_command(&PrunePolicy::KeepGb(AUTO_PRUNE_KEEP_GB))
+    ));
+    script.push("    fi".to_owned());
+    script.push(format!("    {}", disk_space_command()));
+    script.push("fi".to_owned());
+
+    script.join("\n")
+}
+
+fn parse_disk_space(line: &str) -> Option<DiskSpace> {
+    let mut words = line.split_whitespace();
+    if words.next() != Some(DISK_SPACE_MARKER) {
+        return None;
+    }
+
+    let size_kb = words.next()?.parse().ok()?;
+    let free_kb = words.next()?.parse().ok()?;
+    Some(DiskSpace { size_kb, free_kb })
+}
+
+#[test]
+fn disk_space_is_read_from_marked_lines() {
+    assert_eq!(
+        parse_disk_space("disk-space 31440876 10485760"),
+        Some(DiskSpace {
+            size_kb: 31440876,
+            free_kb: 10485760
+        })
+    );
+    assert_eq!(parse_disk_space("Total reclaimed space: 1.2GB"), None);
+    assert_eq!(parse_disk_space("disk-space unknown"), None);
+    assert_eq!(
+        DiskSpace {
+            size_kb: 31457280,
+            free_kb: 10485760
+        }
+        .to_string(),
+        "10.0 GB free of 30.0 GB"
+    );
+}
+
+#[test]
+fn prune_policies_use_docker_filters() {
+    assert_eq!(
+        prune_command(&PrunePolicy::UnusedFor(48)),
+        "docker builder prune --all --force --filter unused-for=48h && docker image prune --force"
+    );
+    assert_eq!(
+        prune_command(&PrunePolicy::KeepGb(20)),
+        "docker builder prune --all --force --keep-storage 20gb"
+    );
+}
+
+// df reports the free space in ~/free, which docker frees up when it prunes
+#[cfg(test)]
+const STUB_DF: &str = "#!/bin/bash
+echo 'Filesystem 1024-blocks Used Available Capacity Mounted on'
+echo \"/dev/xvda1 31457280 0 $(cat ~/free) 0% /\"
+";
+
+#[cfg(test)]
+const STUB_DOCKER: &str = "#!/bin/bash
+echo \"docker $*\" >> ~/docker.log
+echo 'Total reclaimed space: 5GB'
+echo $(( $(cat ~/free) + 5242880 )) > ~/free
+";
+
+#[tokio::test]
+async fn auto_prune_only_runs_when_space_is_low() {
+    let home = tempfile::tempdir().unwrap();
+    std::fs::write(home.path().join("free"), (20 * KB_PER_GB).to_string()).unwrap();
+    let transport = crate::LocalTransport::new(home.path())
+        .with_stubs(&[("df", STUB_DF), ("docker", STUB_DOCKER)]);
+
+    assert!(auto_prune(&transport, 10).await.unwrap().is_none());
+    assert!(!home.path().join("docker.log").exists());
+
+    // Each docker command frees 5 GB, so the first pass only gets from 2 GB to 12 GB
+    std::fs::write(home.path().join("free"), (2 * KB_PER_GB).to_string()).unwrap();
+    let report = auto_prune(&transport, 15).await.unwrap().unwrap();
+    assert_eq!(report.before.free_kb, 2 * KB_PER_GB);
+    assert_eq!(report.after.free_kb, 17 * KB_PER_GB);
+    assert_eq!(
+        std::fs::read_to_string(home.path().join("docker.log")).unwrap(),
+        "docker builder prune --all --force --filter unused-for=24h\n\
+docker image prune --force\n\
+docker builder prune --all --force --keep-storage 10gb\n"
+    );
+    assert_eq!(report.output.len(), 3);
+}
@@ -0,0 +1,154 @@
+use super::start::{ensure_running, StartError};
+use crate::{create_transport, BuilderTransport, Config};
+
+const CHECK_SCRIPT_PATH: &str = "/usr/local/bin/container-builder-idle-check";
+const CRON_FILE_PATH: &str = "/etc/cron.d/container-builder-idle";
+const EC2_USER_HOME: &str = "/home/ec2-user";
+/// Touched by every ship, relative to ec2-user's home directory
+pub(super) const ACTIVITY_MARKER: &str = ".cbuilder-last-activity";
+
+#[derive(Debug)]
+pub enum IdleShutdownError {
+    CouldNotFindConfig,
+    InstanceNotStarted(StartError),
+    InstallFailed(String),
+    FailedSaveConfig,
+}
+
+/// Install (or with `None` remove) a cron job on the instance which shuts it down once
+/// no build has run for the given number of minutes
+pub async fn set_idle_shutdown(minutes: Option<u32>) -> Result<(), IdleShutdownError> {
+    let home_dir = dirs::home_dir().expect("Could not find home directory");
+    let working_dir = home_dir.join(".cbuilder");
+    let props_file_path = working_dir.join("properties.yml");
+    let mut config =
+        Config::read_from_file(&props_file_path).ok_or(IdleShutdownError::CouldNotFindConfig)?;
+
+    ensure_running(&mut config, &working_dir)
+        .await
+        .map_err(IdleShutdownError::InstanceNotStarted)?;
+
+    let install_script = match minutes {
+        Some(minutes) => create_install_script(minutes),
+        None => create_uninstall_script(),
+    };
+
+    let transport = create_transport(&config, &working_dir)
+        .await
+        .map_err(|err| IdleShutdownError::InstallFailed(format!("{:#?}", err)))?;
+    transport
+        .send_stream("sudo bash -s", &mut |writer| {
+            writer.write_all(install_script.as_bytes())
+        })
+        .await
+        .map_err(|err| IdleShutdownError::InstallFailed(format!("{:#?}", err)))?;
+
+    config.set_idle_shutdown_minutes(minutes);
+    config
+        .write_to_file(&props_file_path)
+        .map_err(|_| IdleShutdownError::FailedSaveConfig)?;
+
+    Ok(())
+}
+
+/// Reset the idle clock so the instance isn't shut down while a ship is still preparing
+/// its build
+pub(super) async fn mark_activity(transport: &dyn BuilderTransport) -> Result<(), String> {
+    let output = transport
+        .run_command_streamed(&format!("touch ~/{}", ACTIVITY_MARKER), &mut |_, _| {})
+        .await
+        .map_err(|err| format!("{:#?}", err))?;
+
+    match output.exit_status {
+        0 => Ok(()),
+        _ => Err(output.tail.join("\n")),
+    }
+}
+
+fn create_check_script(minutes: u32) -> String {
+    let mut script = Vec::new();
+    script.push("#!/bin/bash".to_owned());
+    script.push(format!("MARKER={}/{}", EC2_USER_HOME, ACTIVITY_MARKER));
+    // Ships touch the marker before uploading & as their build starts & finishes. A build
+    // longer than the idle time counts as activity for as long as it runs.
+    script.push("if pgrep -f 'builds/.*/script.sh' > /dev/null; then".to_owned());
+    script.push("    touch \"$MARKER\"".to_owned());
+    script.push("    exit 0".to_owned());
+    script.push("fi".to_owned());
+    script.push("[ -e \"$MARKER\" ] || touch \"$MARKER\"".to_owned());
+    script.push(format!(
+        "if [ -z \"$(find \"$MARKER\" -mmin -{})\" ]; then",
+        minutes
+    ));
+    script.push("    shutdown -h now".to_owned());
+    script.push("fi".to_owned());
+
+    script.join("\n")
+}
+
+fn create_install_script(minutes: u32) -> String {
+    let mut script = Vec::new();
+    script.push("set -e".to_owned());
+    script.push(format!("cat > {} <<'EOF'", CHECK_SCRIPT_PATH));
+    script.push(create_check_script(minutes));
+    script.push("EOF".to_owned());
+    script.push(format!("chmod 755 {}", CHECK_SCRIPT_PATH));
+    // Reset the idle time on boot so a freshly started instance isn't stopped straight away
+    script.push(format!("cat > {} <<'EOF'", CRON_FILE_PATH));
+    script.push(format!("* * * * * root {}", CHECK_SCRIPT_PATH));
+    script.push(format!(
+        "@reboot ec2-user touch {}/{}",
+        EC2_USER_HOME, ACTIVITY_MARKER
+    ));
+    script.push("EOF".to_owned());
+    script.push(format!(
+        "sudo -u ec2-user touch {}/{}",
+        EC2_USER_HOME, ACTIVITY_MARKER
+    ));
+
+    script.join("\n")
+}
+
+fn create_uninstall_script() -> String {
+    format!("rm -f {} {}", CRON_FILE_PATH, CHECK_SCRIPT_PATH)
+}
+
+#[test]
+fn idle_check_shuts_down_after_the_configured_minutes() {
+    let script = create_check_script(45);
+
+    assert!(script.contains("find \"$MARKER\" -mmin -45"));
+    assert!(script.contains("shutdown -h now"));
+}
+
+#[test]
+fn install_script_writes_the_check_and_cron_job() {
+    let script = create_install_script(30);
+
+    assert!(script.contains(&format!("cat > {} <<'EOF'", CHECK_SCRIPT_PATH)));
+    assert!(script.contains(&format!("* * * * * root {}", CHECK_SCRIPT_PATH)));
+    assert!(script.contains("-mmin -30"));
+}
+
+#[tokio::test]
+async fn mark_activity_resets_the_idle_clock() {
+    let home = tempfile::tempdir().unwrap();
+    let marker = home.path().join(ACTIVITY_MARKER);
+    std::process::Command::new("touch")
+        .args(["-d", "2 hours ago"])
+        .arg(&marker)
+        .status()
+        .unwrap();
+
+    mark_activity(&crate::LocalTransport::new(home.path()))
+        .await
+        .unwrap();
+
+    let idle_for = std::fs::metadata(&marker)
+        .unwrap()
+        .modified()
+        .unwrap()
+        .elapsed()
+        .unwrap_or_default();
+    assert!(idle_for < std::time::Duration::from_secs(60));
+}
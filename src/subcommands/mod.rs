@@ -1,15 +1,25 @@
 mod add_account;
+mod allow_ip;
 mod bootstrap;
+mod cache;
 mod connect;
+mod idle_shutdown;
+mod reconfigure;
 mod ship;
 mod start;
+mod status;
 mod stop;
 mod uninstall;
 
 pub use add_account::run_add_account;
-pub use bootstrap::{run_bootstrap, BootstrapErrors};
+pub use allow_ip::run_allow_ip;
+pub use bootstrap::{run_bootstrap, BootstrapErrors, InstanceSettings};
+pub use cache::{cache_usage, prune_cache, set_auto_prune, PrunePolicy};
 pub use connect::run_connect;
-pub use ship::{ship, ShipError};
+pub use idle_shutdown::set_idle_shutdown;
+pub use reconfigure::{run_reconfigure, AmiChoice, Reconfiguration};
+pub use ship::{ship, BuildOptions, RepositorySettings, ShipError};
 pub use start::run_start;
+pub use status::get_status;
 pub use stop::run_stop;
 pub use uninstall::{uninstall, UninstallError};
@@ -0,0 +1,325 @@
+use super::allow_ip::{add_ssh_ingress, resolve_allowed_cidrs, sync_ssh_ingress};
+use super::bootstrap::{capture_host_keys, get_amazon_linux_2_ami};
+use super::start::{ensure_running, StartError};
+use crate::{
+    Architecture, AwsClientFactory, CfnClient, Config, EC2Client, HostKeys, SimpleParameter, Tag,
+    Template, TransportKind,
+};
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum ReconfigureError {
+    CouldNotFindConfig,
+    NothingToChange,
+    InstanceNotFound,
+    FailedFindAmi,
+    FailedLoadTemplate(String),
+    StackMissingSsmSupport(String),
+    StackUpdateFailed(String),
+    FailedAllowIngress(String),
+    FailedSaveConfig,
+    FailedPinHostKeys(String),
+    InstanceNotStarted(StartError),
+}
+
+pub enum AmiChoice {
+    Latest,
+    Id(String),
+}
+
+/// The settings to change. Anything left as None keeps its current value.
+pub struct Reconfiguration {
+    pub instance_type: Option<String>,
+    pub volume_size: Option<u32>,
+    pub volume_type: Option<String>,
+    pub ami: Option<AmiChoice>,
+    pub allowed_cidrs: Option<Vec<String>>,
+    pub tags: Option<Vec<Tag>>,
+    pub transport: Option<TransportKind>,
+}
+
+/// How the SSH ingress rules change once the stack has been updated
+#[derive(Debug, PartialEq)]
+enum IngressPlan {
+    Unchanged,
+    /// Make the rules match these ranges exactly, or the caller's IP over SSH if there are none
+    Replace(Vec<String>),
+    /// Allow the caller's IP alongside the existing rules
+    AddCallerIp,
+    /// The config is from before the allowed ranges were saved, so it's unknown which rules
+    /// are still wanted
+    Unrecorded,
+}
+
+impl Reconfiguration {
+    fn is_empty(&self) -> bool {
+        self.instance_type.is_none()
+            && self.volume_size.is_none()
+            && self.volume_type.is_none()
+            && self.ami.is_none()
+            && self.allowed_cidrs.is_none()
+            && self.tags.is_none()
+            && self.transport.is_none()
+    }
+}
+
+/// Returns false if the builder already had these settings
+pub async fn run_reconfigure(
+    changes: Reconfiguration,
+    template_path: Option<&Path>,
+) -> Result<bool, ReconfigureError> {
+    if changes.is_empty() && template_path.is_none() {
+        return Err(ReconfigureError::NothingToChange);
+    }
+
+    let home_dir = dirs::home_dir().expect("Could not find home directory");
+    let working_dir = home_dir.join(".cbuilder");
+    let mut config = Config::read_from_file(&working_dir.join("properties.yml"))
+        .ok_or(ReconfigureError::CouldNotFindConfig)?;
+
+    let aws = AwsClientFactory::new(config.get_base_profile(), config.get_region());
+    let cfn_client = CfnClient::new(&aws);
+    let ec2_client = EC2Client::new(&aws);
+
+    let instance_id = cfn_client
+        .get_instance_id()
+        .await
+        .ok_or(ReconfigureError::InstanceNotFound)?;
+
+    let mut parameters = Vec::new();
+    if let Some(instance_type) = &changes.instance_type {
+        parameters.push(SimpleParameter::new(
+            "InstanceType".to_owned(),
+            instance_type.clone(),
+        ));
+    }
+    if let Some(volume_size) = changes.volume_size {
+        parameters.push(SimpleParameter::new(
+            "VolumeSize".to_owned(),
+            volume_size.to_string(),
+        ));
+    }
+    if let Some(volume_type) = &changes.volume_type {
+        parameters.push(SimpleParameter::new(
+            "VolumeType".to_owned(),
+            volume_type.clone(),
+        ));
+    }
+
+    let ami = match changes.ami {
+        Some(AmiChoice::Id(ami)) => Some(ami),
+        Some(AmiChoice::Latest) => {
+            // Use the architecture of the instance type the builder will end up with
+            let instance_type = match changes.instance_type.clone() {
+                Some(instance_type) => instance_type,
+                None => {
+                    ec2_client
+                        .get_instance_details(instance_id.clone())
+                        .await
+                        .ok_or(ReconfigureError::InstanceNotFound)?
+                        .instance_type
+                }
+            };
+            let architectures = ec2_client
+                .get_instance_type_architectures(instance_type)
+                .await
+                .ok_or(ReconfigureError::FailedFindAmi)?;
+            let architecture = if architectures.iter().any(|arch| arch == "x86_64") {
+                Architecture::X86_64
+            } else {
+                Architecture::Arm64
+            };
+
+            let images = ec2_client
+                .get_amazon_linux_2_ami(architecture)
+                .await
+                .ok_or(ReconfigureError::FailedFindAmi)?;
+            Some(
+                get_amazon_linux_2_ami(images)
+                    .await
+                    .map_err(|_| ReconfigureError::FailedFindAmi)?,
+            )
+        }
+        None => None,
+    };
+    if let Some(ami) = ami {
+        parameters.push(SimpleParameter::new("AmiId".to_owned(), ami));
+    }
+
+    // Stacks from before SSM was supported have no staging bucket or SSM permissions, so
+    // switching them would close SSH and leave no way back in. A new template adds them.
+    let transport = changes.transport.unwrap_or_else(|| config.get_transport());
+    if transport == TransportKind::Ssm && template_path.is_none() {
+        ensure_ssm_support(&cfn_client).await?;
+    }
+
+    // Keep the stack's template, which may have been customised, unless one is given
+    let template = template_path
+        .map(|path| Template::Instance.load(Some(path)))
+        .transpose()
+        .map_err(ReconfigureError::FailedLoadTemplate)?;
+
+    let updated = cfn_client
+        .reconfigure_stack(
+            "container-builder".to_owned(),
+            template,
+            &parameters,
+            changes.tags.as_deref(),
+            20 * 60,
+        )
+        .await
+        .map_err(|err| ReconfigureError::StackUpdateFailed(err.to_string()))?;
+    if transport == TransportKind::Ssm {
+        ensure_ssm_support(&cfn_client).await?;
+    }
+
+    let previous_instance_id = instance_id;
+    let instance_id = cfn_client
+        .get_instance_id()
+        .await
+        .ok_or(ReconfigureError::InstanceNotFound)?;
+
+    let ingress = plan_ingress(
+        changes.allowed_cidrs,
+        changes.transport,
+        config.get_transport(),
+        instance_id != previous_instance_id,
+        config.get_allowed_cidrs(),
+    );
+    let ingress_changed = !matches!(ingress, IngressPlan::Unchanged | IngressPlan::Unrecorded);
+    let transport_changed = transport != config.get_transport();
+    match ingress {
+        IngressPlan::Unchanged => {}
+        IngressPlan::Replace(cidrs) => {
+            let allowed_cidrs = match transport {
+                TransportKind::Ssh => resolve_allowed_cidrs(cidrs)
+                    .await
+                    .map_err(ReconfigureError::FailedAllowIngress)?,
+                TransportKind::Ssm => cidrs,
+            };
+            sync_ssh_ingress(&ec2_client, instance_id.clone(), &allowed_cidrs)
+                .await
+                .map_err(ReconfigureError::FailedAllowIngress)?;
+            config.set_allowed_cidrs(allowed_cidrs);
+        }
+        IngressPlan::AddCallerIp => {
+            let caller_cidrs = resolve_allowed_cidrs(vec![])
+                .await
+                .map_err(ReconfigureError::FailedAllowIngress)?;
+            let allowed_cidrs = add_ssh_ingress(&ec2_client, instance_id.clone(), &caller_cidrs)
+                .await
+                .map_err(ReconfigureError::FailedAllowIngress)?;
+            config.set_allowed_cidrs(allowed_cidrs);
+        }
+        IngressPlan::Unrecorded => println!(
+            "The SSH ingress rules were left as they are. Run `builder allow-ip` to limit them \
+             to your IP, or `builder allow-ip --cidr <range>` to allow other ranges."
+        ),
+    }
+
+    // A replacement instance has new host keys
+    if instance_id != previous_instance_id {
+        let host_keys = HostKeys::new(working_dir.join("known_hosts"));
+        let instance_ip = ec2_client.get_instance_ip(instance_id.clone()).await;
+        match (transport, instance_ip) {
+            (TransportKind::Ssh, Some(instance_ip)) => {
+                capture_host_keys(&ec2_client, instance_id.clone(), &instance_ip, &host_keys)
+                    .await
+                    .map(|_| ())
+            }
+            _ => host_keys.forget(),
+        }
+        .map_err(|err| ReconfigureError::FailedPinHostKeys(format!("{:?}", err)))?;
+    }
+
+    config.set_transport(transport);
+    config
+        .write_to_file(&working_dir.join("properties.yml"))
+        .map_err(|_| ReconfigureError::FailedSaveConfig)?;
+
+    // Changing the AMI or volume replaces the instance and changing the type restarts it,
+    // so the IP in the config is refreshed. A stopped builder is left stopped.
+    let state = ec2_client.get_instance_state(instance_id).await;
+    if updated && state.as_deref() != Some("stopped") {
+        ensure_running(&mut config, &working_dir)
+            .await
+            .map_err(ReconfigureError::InstanceNotStarted)?;
+    }
+
+    Ok(updated || ingress_changed || transport_changed)
+}
+
+async fn ensure_ssm_support(cfn_client: &CfnClient) -> Result<(), ReconfigureError> {
+    match cfn_client.get_staging_bucket().await {
+        Some(_) => Ok(()),
+        None => Err(ReconfigureError::StackMissingSsmSupport(
+            "The builder stack has no staging bucket for SSM. Save the current template with \
+             `builder template print instance > instance.yml` and pass it with \
+             `--template instance.yml`"
+                .to_owned(),
+        )),
+    }
+}
+
+/// Ingress is only changed when asked to or when the instance was replaced, which may have
+/// given it a new security group. SSM needs no ingress so SSH is closed unless ranges are
+/// explicitly given. The ranges saved in the config are only reapplied over SSH if there
+/// are some, as older configs didn't save them.
+fn plan_ingress(
+    requested_cidrs: Option<Vec<String>>,
+    requested_transport: Option<TransportKind>,
+    current_transport: TransportKind,
+    replaced: bool,
+    saved_cidrs: Vec<String>,
+) -> IngressPlan {
+    if let Some(cidrs) = requested_cidrs {
+        return IngressPlan::Replace(cidrs);
+    }
+    if requested_transport.is_none() && !replaced {
+        return IngressPlan::Unchanged;
+    }
+
+    match requested_transport.unwrap_or(current_transport) {
+        TransportKind::Ssm => IngressPlan::Replace(vec![]),
+        TransportKind::Ssh if !saved_cidrs.is_empty() => IngressPlan::Replace(saved_cidrs),
+        // Switching from SSM, where nothing was allowed, so SSH needs opening for the caller
+        TransportKind::Ssh if requested_transport.is_some() => IngressPlan::AddCallerIp,
+        TransportKind::Ssh => IngressPlan::Unrecorded,
+    }
+}
+
+#[test]
+fn ingress_is_only_changed_when_needed() {
+    let saved = vec!["10.0.0.0/8".to_owned()];
+    let ssh = TransportKind::Ssh;
+
+    assert_eq!(
+        plan_ingress(None, None, ssh, false, vec![]),
+        IngressPlan::Unchanged
+    );
+    assert_eq!(
+        plan_ingress(None, None, ssh, false, saved.clone()),
+        IngressPlan::Unchanged
+    );
+    assert_eq!(
+        plan_ingress(Some(saved.clone()), None, ssh, false, vec![]),
+        IngressPlan::Replace(saved.clone())
+    );
+    assert_eq!(
+        plan_ingress(None, None, ssh, true, saved.clone()),
+        IngressPlan::Replace(saved.clone())
+    );
+    // Configs from before the ranges were saved never have other ranges revoked
+    assert_eq!(
+        plan_ingress(None, None, ssh, true, vec![]),
+        IngressPlan::Unrecorded
+    );
+    assert_eq!(
+        plan_ingress(None, Some(ssh), TransportKind::Ssm, false, vec![]),
+        IngressPlan::AddCallerIp
+    );
+    assert_eq!(
+        plan_ingress(None, Some(TransportKind::Ssm), ssh, false, saved),
+        IngressPlan::Replace(vec![])
+    );
+}
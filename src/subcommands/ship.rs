@@ -1,80 +1,324 @@
-use crate::{Config, DockerIgnore, SSHClient};
+use super::cache::auto_prune;
+use super::idle_shutdown::{mark_activity, ACTIVITY_MARKER};
+use super::start::{ensure_running, StartError};
+use crate::{
+    context_name, create_transport, find_registry_credentials, git_tags, is_valid_tag,
+    BuilderTransport, Config, ContextManifest, DockerIgnore, DockerIgnoreError, GitTagError,
+    OutputStream, RegistryCredentials, RegistryCredentialsError, RegistryKind, RegistryUri,
+    RegistryUriError, Script, ScriptCommand,
+};
+use chrono::Utc;
 use flate2::write::GzEncoder;
 use flate2::Compression;
 use std::fs::File;
 use std::fs::{read_dir, DirEntry};
 use std::io::Write;
-use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 
 #[derive(Debug)]
 pub enum ShipError {
-    RegistryUriMalformed,
+    RegistryUriInvalid(RegistryUriError),
+    RegistryCredentialsFailed(RegistryCredentialsError),
+    TagInvalid(String),
     ArchiveCreationFailed(String),
-    ScriptCreateFailed,
+    DockerIgnoreInvalid(String),
     ConfigFileNotOpened,
-    ScriptExitCodeError(String),
+    InstanceNotStarted(StartError),
+    TransportFailed(String),
+    ScriptExitCodeError {
+        exit_status: i32,
+        output_tail: Vec<String>,
+    },
+    RunScriptError(String),
     SendFileError(String),
+    SyncFailed(String),
+    GitTagsFailed(GitTagError),
+    NoTags,
+    DockerfileOutsideContext(String),
+    LifecyclePolicyInvalid(String),
 }
 
-pub fn ship(
+/// How ECR repositories which don't exist yet are created
+#[derive(Debug, Default)]
+pub struct RepositorySettings {
+    pub scan_on_push: bool,
+    pub immutable_tags: bool,
+    /// Lifecycle policy JSON
+    pub lifecycle_policy: Option<String>,
+}
+
+/// Options passed on to `docker build`
+#[derive(Debug, Default)]
+pub struct BuildOptions {
+    /// `KEY=VALUE` pairs
+    pub build_args: Vec<String>,
+    pub target: Option<String>,
+    /// Path to the Dockerfile relative to the context
+    pub file: Option<String>,
+    pub platform: Option<String>,
+    /// `KEY=VALUE` pairs
+    pub labels: Vec<String>,
+    pub no_cache: bool,
+    /// Anything else, passed after the other options
+    pub extra_args: Vec<String>,
+}
+
+impl BuildOptions {
+    fn docker_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        for build_arg in &self.build_args {
+            args.push("--build-arg".to_owned());
+            args.push(build_arg.clone());
+        }
+        for (flag, value) in &[
+            ("--target", &self.target),
+            ("--file", &self.file),
+            ("--platform", &self.platform),
+        ] {
+            if let Some(value) = value {
+                args.push(flag.to_string());
+                args.push(value.clone());
+            }
+        }
+        for label in &self.labels {
+            args.push("--label".to_owned());
+            args.push(label.clone());
+        }
+        if self.no_cache {
+            args.push("--no-cache".to_owned());
+        }
+        args.extend(self.extra_args.iter().cloned());
+        args
+    }
+}
+
+/// Everything needed to run a build, worked out before the instance is started
+struct ShipPlan {
+    target_dir: PathBuf,
+    files: Vec<PathBuf>,
+    registries: Vec<RegistryUri>,
+    /// Logins for registries other than ECR, by host
+    credentials: Vec<(String, RegistryCredentials)>,
+    build_options: BuildOptions,
+    tags: Vec<String>,
+    sync: bool,
+    create_repo: Option<RepositorySettings>,
+}
+
+impl ShipPlan {
+    fn new(
+        working_dir: &Path,
+        path: String,
+        registry_uris: Vec<String>,
+        build_options: BuildOptions,
+        mut tags: Vec<String>,
+        tag_from_git: bool,
+        sync: bool,
+    ) -> Result<ShipPlan, ShipError> {
+        let target_dir = PathBuf::from(path);
+
+        if tag_from_git {
+            for tag in git_tags(&target_dir).map_err(ShipError::GitTagsFailed)? {
+                if !tags.contains(&tag) {
+                    tags.push(tag);
+                }
+            }
+        }
+        if tags.is_empty() {
+            return Err(ShipError::NoTags);
+        }
+        if let Some(tag) = tags.iter().find(|tag| !is_valid_tag(tag)) {
+            return Err(ShipError::TagInvalid(tag.clone()));
+        }
+        // Find all files in directory
+        let all_files = get_all_files(&target_dir);
+
+        let registries = registry_uris
+            .into_iter()
+            .map(|uri| RegistryUri::parse(&uri))
+            .collect::<Result<Vec<RegistryUri>, RegistryUriError>>()
+            .map_err(ShipError::RegistryUriInvalid)?;
+
+        // Find credentials now so a missing login fails before the instance is started
+        let mut credentials: Vec<(String, RegistryCredentials)> = vec![];
+        for registry in &registries {
+            let host = registry.get_host();
+            let is_new_host = !credentials.iter().any(|(known, _)| known == host);
+            if *registry.get_kind() == RegistryKind::Generic && is_new_host {
+                let found = find_registry_credentials(host, working_dir)
+                    .map_err(ShipError::RegistryCredentialsFailed)?;
+                credentials.push((host.to_owned(), found));
+            }
+        }
+
+        // Remove the files from .dockerignore
+        let mut keep_files = vec!["Dockerfile", ".dockerignore"];
+        if let Some(file) = &build_options.file {
+            // The Dockerfile has to be sent with the rest of the context
+            if Path::new(file).is_absolute() || file.split('/').any(|part| part == "..") {
+                return Err(ShipError::DockerfileOutsideContext(file.clone()));
+            }
+            keep_files.push(file);
+        }
+
+        let files = match DockerIgnore::new(target_dir.join(".dockerignore")) {
+            Ok(ignore) => ignore
+                .keep_files(&keep_files)
+                .filter_files(&target_dir, &all_files),
+            Err(DockerIgnoreError::FileReadFailed(_)) => all_files,
+            Err(DockerIgnoreError::InvalidPattern(err)) => {
+                return Err(ShipError::DockerIgnoreInvalid(err))
+            }
+        };
+
+        Ok(ShipPlan {
+            target_dir,
+            files,
+            registries,
+            credentials,
+            build_options,
+            tags,
+            sync,
+            create_repo: None,
+        })
+    }
+}
+
+/// Build the image once & push it with every tag to every registry
+pub async fn ship(
     path: String,
-    registry_uri: String,
-    additional_args: Option<Vec<String>>,
-    tag: String,
+    registry_uris: Vec<String>,
+    build_options: BuildOptions,
+    tags: Vec<String>,
+    tag_from_git: bool,
+    sync: bool,
+    create_repo: Option<RepositorySettings>,
 ) -> Result<(), ShipError> {
-    let target_dir = Path::new(&path);
-    // Find all files in directory
-    let all_files = get_all_files(target_dir);
-
-    // Get the account from the registry_uri
-    let account = registry_uri
-        .split(".")
-        .nth(0)
-        .ok_or(ShipError::RegistryUriMalformed)
-        .map(|acc| acc.to_owned())?;
-
-    // Remove the files from .dockerignore
-    let filtered_files = DockerIgnore::new(target_dir.join(".dockerignore"))
-        .map_or(all_files.clone(), |ignore| ignore.filter_files(&all_files));
-
-    tar_files(&filtered_files, &target_dir)
-        .map_err(|err| ShipError::ArchiveCreationFailed(err.to_string()))?;
-
-    // Write a script
-    create_script(account, registry_uri, additional_args, tag)?;
+    // Catch a broken policy before the build rather than after it
+    if let Some(policy) = create_repo
+        .as_ref()
+        .and_then(|settings| settings.lifecycle_policy.as_ref())
+    {
+        serde_json::from_str::<serde_json::Value>(policy)
+            .map_err(|err| ShipError::LifecyclePolicyInvalid(err.to_string()))?;
+    }
 
     let home_dir = dirs::home_dir().expect("Could not find home directory");
     let working_dir = home_dir.join(".cbuilder");
 
-    let config = Config::read_from_file(&working_dir.join("properties.yml"))
+    let plan = ShipPlan {
+        create_repo,
+        ..ShipPlan::new(
+            &working_dir,
+            path,
+            registry_uris,
+            build_options,
+            tags,
+            tag_from_git,
+            sync,
+        )?
+    };
+
+    let mut config = Config::read_from_file(&working_dir.join("properties.yml"))
         .ok_or(ShipError::ConfigFileNotOpened)?;
-    let ssh_client = SSHClient::new(
-        config.get_instance_ip(),
-        working_dir.join("ContainerBuilderKey.pem"),
-    );
 
-    // Ship archive
-    ssh_client
-        .send_file(Path::new("archive.tar.gz"), "archive.tar.gz".to_owned())
-        .map_err(|err| ShipError::SendFileError(format!("{:#?}", err)))?;
+    // Start the instance if it was stopped since the last ship
+    ensure_running(&mut config, &working_dir)
+        .await
+        .map_err(ShipError::InstanceNotStarted)?;
+
+    let transport = create_transport(&config, &working_dir)
+        .await
+        .map_err(|err| ShipError::TransportFailed(format!("{:#?}", err)))?;
+
+    // Pruning & uploading can take a while, so an idle instance could otherwise shut down
+    // before the build script marks it as busy
+    mark_activity(transport.as_ref())
+        .await
+        .map_err(ShipError::TransportFailed)?;
+
+    // A full disk fails the build, so making space is worth a slower build. Failing to
+    // prune shouldn't stop a build which may still fit.
+    if let Some(min_free_gb) = config.get_cache_min_free_gb() {
+        match auto_prune(transport.as_ref(), min_free_gb).await {
+            Ok(Some(report)) => println!(
+                "Pruned the docker cache as less than {} GB was free. Before: {}. After: {}",
+                min_free_gb, report.before, report.after
+            ),
+            Ok(None) => {}
+            Err(err) => println!("Failed to prune the docker cache with error: {:#?}", err),
+        }
+    }
+
+    run_ship(transport.as_ref(), plan).await
+}
+
+/// Send the context to the builder, then build & push the image
+async fn run_ship(transport: &dyn BuilderTransport, plan: ShipPlan) -> Result<(), ShipError> {
+    // Every build gets its own directory on the instance so parallel ships don't collide
+    let build_dir = create_build_dir_name();
+
+    let context_dir = if plan.sync {
+        sync_context(transport, &plan.target_dir, &plan.files).await?
+    } else {
+        // Stream the archive straight into tar on the instance
+        let context_dir = format!("{}/context", build_dir);
+        transport
+            .send_stream(
+                &format!("mkdir -p {dir} && tar -xzf - -C {dir}", dir = context_dir),
+                &mut |writer| tar_files(&plan.files, &plan.target_dir, writer),
+            )
+            .await
+            .map_err(|err| ShipError::ArchiveCreationFailed(format!("{:#?}", err)))?;
+        context_dir
+    };
+
+    // Write a script
+    let script = create_script(
+        &build_dir,
+        &context_dir,
+        &plan.registries,
+        &plan.build_options,
+        &plan.tags,
+        plan.create_repo.as_ref(),
+    );
 
     // Ship script
-    ssh_client
-        .send_file(Path::new("script.sh"), "script.sh".to_owned())
+    transport
+        .send_stream(&script_upload_command(&build_dir), &mut |writer| {
+            writer.write_all(script.as_bytes())
+        })
+        .await
         .map_err(|err| ShipError::SendFileError(format!("{:#?}", err)))?;
 
-    // Run script
-    let was_success = ssh_client
-        .run_command("./script.sh > log".to_owned())
-        .map_err(|err| ShipError::ScriptExitCodeError(format!("{:#?}", err)))?;
+    // Run script, streaming the build output as it happens
+    let command = format!("{}/script.sh", build_dir);
+    let mut on_line = |stream, line: &str| match stream {
+        OutputStream::Stdout => println!("{}", line),
+        OutputStream::Stderr => eprintln!("{}", line),
+    };
+    let output = if plan.credentials.is_empty() {
+        transport.run_command_streamed(&command, &mut on_line).await
+    } else {
+        // Registry logins are passed on stdin so they are never stored on the instance
+        let logins: String = plan
+            .credentials
+            .iter()
+            .map(|(host, login)| format!("{}\n{}\n{}\n", host, login.username, login.password))
+            .collect();
+        transport
+            .run_command_with_input(&command, logins.as_bytes(), &mut on_line)
+            .await
+    }
+    .map_err(|err| ShipError::RunScriptError(format!("{:#?}", err)))?;
 
-    if was_success {
+    if output.exit_status == 0 {
         Ok(())
     } else {
-        Err(ShipError::ScriptExitCodeError(
-            "Script exited with non zero error code".to_owned(),
-        ))
+        Err(ShipError::ScriptExitCodeError {
+            exit_status: output.exit_status,
+            output_tail: output.tail,
+        })
     }
 }
 
@@ -91,10 +335,109 @@ fn get_all_files(path: &Path) -> Vec<PathBuf> {
     files
 }
 
-fn tar_files(all_files: &Vec<PathBuf>, target_dir: &Path) -> std::io::Result<()> {
+/// Bring the persistent copy of this context on the instance up to date by sending only
+/// the files whose content hash differs from the manifest stored alongside it
+async fn sync_context(
+    transport: &dyn BuilderTransport,
+    target_dir: &Path,
+    files: &[PathBuf],
+) -> Result<String, ShipError> {
+    let name = context_name(target_dir).map_err(|err| ShipError::SyncFailed(err.to_string()))?;
+    let context_dir = format!("contexts/{}", name);
+    let manifest_path = format!("contexts/{}.manifest", name);
+
+    let local_manifest = ContextManifest::from_files(target_dir, files)
+        .map_err(|err| ShipError::ArchiveCreationFailed(err.to_string()))?;
+    // Resend everything if the context was removed but its manifest was left behind
+    let context_exists = transport
+        .exists(&context_dir)
+        .await
+        .map_err(|err| ShipError::SyncFailed(format!("{:#?}", err)))?;
+    let remote_manifest = if context_exists {
+        transport
+            .read_file(&manifest_path)
+            .await
+            .map_err(|err| ShipError::SyncFailed(format!("{:#?}", err)))?
+            .map(|data| ContextManifest::parse(&String::from_utf8_lossy(&data)))
+            .unwrap_or_else(|| ContextManifest::parse(""))
+    } else {
+        ContextManifest::parse("")
+    };
+
+    let diff = local_manifest.diff(&remote_manifest);
+    println!(
+        "Syncing context: {} changed, {} deleted, {} unchanged files",
+        diff.changed.len(),
+        diff.deleted.len(),
+        files.len() - diff.changed.len()
+    );
+
+    if diff.changed.is_empty() && diff.deleted.is_empty() {
+        return Ok(context_dir);
+    }
+
+    // Remove the manifest while the files change so an interrupted sync is redone in full
+    let changed_files: Vec<PathBuf> = diff
+        .changed
+        .iter()
+        .map(|path| target_dir.join(path))
+        .collect();
+    transport
+        .send_stream(
+            &format!(
+                "rm -f {manifest} && mkdir -p {dir} && tar -xzf - -C {dir}",
+                manifest = manifest_path,
+                dir = context_dir
+            ),
+            &mut |writer| tar_files(&changed_files, target_dir, writer),
+        )
+        .await
+        .map_err(|err| ShipError::SyncFailed(format!("{:#?}", err)))?;
+
+    if !diff.deleted.is_empty() {
+        transport
+            .send_stream(
+                &format!(
+                    "cd {} && xargs -0 rm -f -- && find . -mindepth 1 -type d -empty -delete",
+                    context_dir
+                ),
+                &mut |writer| writer.write_all(diff.deleted.join("\0").as_bytes()),
+            )
+            .await
+            .map_err(|err| ShipError::SyncFailed(format!("{:#?}", err)))?;
+    }
+
+    transport
+        .send_data(
+            local_manifest.to_manifest_string().as_bytes(),
+            &manifest_path,
+            0o600,
+        )
+        .await
+        .map_err(|err| ShipError::SyncFailed(format!("{:#?}", err)))?;
+
+    Ok(context_dir)
+}
+
+fn create_build_dir_name() -> String {
+    format!(
+        "builds/{}-{}",
+        Utc::now().format("%Y%m%d%H%M%S%f"),
+        std::process::id()
+    )
+}
+
+/// A synced context lives outside the build dir, so nothing else may have created it yet
+fn script_upload_command(build_dir: &str) -> String {
+    format!(
+        "mkdir -p {dir} && cat > {dir}/script.sh && chmod 700 {dir}/script.sh",
+        dir = build_dir
+    )
+}
+
+fn tar_files<W: Write>(all_files: &[PathBuf], target_dir: &Path, writer: W) -> std::io::Result<()> {
     // Zip up the path
-    let tar_file = File::create("archive.tar.gz")?;
-    let enc = GzEncoder::new(&tar_file, Compression::default());
+    let enc = GzEncoder::new(writer, Compression::default());
     let mut tar = tar::Builder::new(enc);
 
     if let Some(err) = all_files
@@ -109,63 +452,653 @@ fn tar_files(all_files: &Vec<PathBuf>, target_dir: &Path) -> std::io::Result<()>
     {
         return err;
     }
-    Ok(())
+
+    // Finish explicitly so errors writing the end of the archive aren't lost
+    tar.into_inner()?.finish()?.flush()
 }
 
 fn create_script(
-    target_account: String,
-    registry_uri: String,
-    additional_args: Option<Vec<String>>,
-    tag: String,
-) -> Result<(), ShipError> {
-    // Set additional args
-    let build_args = match additional_args {
-        Some(args) => args.join(" "),
-        None => "".to_owned(),
+    build_dir: &str,
+    context_dir: &str,
+    registries: &[RegistryUri],
+    build_options: &BuildOptions,
+    tags: &[String],
+    create_repo: Option<&RepositorySettings>,
+) -> String {
+    // A tag in the registry URI is used instead of the shared tags
+    let images: Vec<String> = registries
+        .iter()
+        .flat_map(|registry| match registry.get_tag() {
+            Some(tag) => vec![registry.image(tag)],
+            None => tags.iter().map(|tag| registry.image(tag)).collect(),
+        })
+        .collect();
+
+    let needs_logins = registries
+        .iter()
+        .any(|registry| *registry.get_kind() == RegistryKind::Generic);
+
+    let mut script = Script::new();
+    let mut clean_up = ScriptCommand::new("rm").arg("-rf").home_path(build_dir);
+    if needs_logins {
+        // docker login stores credentials in its config, so keep that in memory
+        script.raw(
+            "DOCKER_CONFIG=$(mktemp -d /dev/shm/cbuilder-docker.XXXXXX)\nexport DOCKER_CONFIG",
+        );
+        clean_up = clean_up.var("DOCKER_CONFIG");
+    }
+    // Reset the idle clock as the build starts & finishes, as the idle check only runs
+    // once a minute & could miss a short build
+    let mark_activity = || ScriptCommand::new("touch").home_path(ACTIVITY_MARKER);
+    script
+        .on_exit(vec![clean_up, mark_activity()])
+        .run(mark_activity())
+        .run(ScriptCommand::new("cd").home_path(context_dir))
+        // Keep the assumed role profiles private to this build
+        .export_home_path("AWS_CONFIG_FILE", &format!("{}/aws-config", build_dir));
+
+    // Each account has its own push role, and each registry host needs its own login
+    let mut accounts: Vec<&str> = vec![];
+    let mut hosts: Vec<&str> = vec![];
+    for registry in registries {
+        let (account, region) = match registry.get_kind() {
+            RegistryKind::Ecr { account, region } => (account.as_str(), region),
+            RegistryKind::Generic => continue,
+        };
+        let profile = format!("account_{}", account);
+        if !accounts.contains(&account) {
+            accounts.push(account);
+            let role_arn = format!("arn:aws:iam::{}:role/ContainerBuilderPushRole", account);
+            script
+                .run(
+                    ScriptCommand::new("aws")
+                        .args(["configure", "set"])
+                        .arg(format!("profile.{}.role_arn", profile))
+                        .arg(role_arn),
+                )
+                .run(
+                    ScriptCommand::new("aws")
+                        .args(["configure", "set"])
+                        .arg(format!("profile.{}.credential_source", profile))
+                        .arg("Ec2InstanceMetadata"),
+                );
+        }
+        if !hosts.contains(&registry.get_host()) {
+            hosts.push(registry.get_host());
+            script.pipe(
+                ScriptCommand::new("aws")
+                    .args(["ecr", "get-login-password", "--profile"])
+                    .arg(&profile)
+                    .arg("--region")
+                    .arg(region.name()),
+                ScriptCommand::new("docker")
+                    .args(["login", "--username", "AWS", "--password-stdin"])
+                    .arg(registry.get_host()),
+            );
+        }
+    }
+
+    if needs_logins {
+        // Read host, username & password lines from stdin without tracing them
+        script.raw(
+            "set +x
+while IFS= read -r host && IFS= read -r username && IFS= read -r password; do
+printf '%s' \"$password\" | docker login --username \"$username\" --password-stdin \"$host\"
+done
+set -x",
+        );
+    }
+
+    // Create missing repositories before the build, so a push can't fail at the end of it
+    if let Some(settings) = create_repo {
+        let mut repositories: Vec<&RegistryUri> = vec![];
+        for registry in registries {
+            let is_new = !repositories.iter().any(|known| {
+                known.get_host() == registry.get_host()
+                    && known.get_repository() == registry.get_repository()
+            });
+            if let (RegistryKind::Ecr { .. }, true) = (registry.get_kind(), is_new) {
+                repositories.push(registry);
+                create_repository(&mut script, registry, settings);
+            }
+        }
+    }
+
+    let mut docker_build = ScriptCommand::new("docker").arg("build");
+    for image in &images {
+        docker_build = docker_build.arg("-t").arg(image);
+    }
+    script.run(docker_build.args(build_options.docker_args()).arg("."));
+    for image in &images {
+        script.run(ScriptCommand::new("docker").arg("push").arg(image));
+    }
+
+    script.render()
+}
+
+fn create_repository(script: &mut Script, registry: &RegistryUri, settings: &RepositorySettings) {
+    let (account, region) = match registry.get_kind() {
+        RegistryKind::Ecr { account, region } => (account, region),
+        RegistryKind::Generic => return,
+    };
+    let ecr = |action: &str| {
+        ScriptCommand::new("aws")
+            .arg("ecr")
+            .arg(action)
+            .arg("--profile")
+            .arg(format!("account_{}", account))
+            .arg("--region")
+            .arg(region.name())
+    };
+
+    let mut commands = vec![ecr("create-repository")
+        .arg("--repository-name")
+        .arg(registry.get_repository())
+        .arg("--image-scanning-configuration")
+        .arg(format!("scanOnPush={}", settings.scan_on_push))
+        .arg("--image-tag-mutability")
+        .arg(if settings.immutable_tags {
+            "IMMUTABLE"
+        } else {
+            "MUTABLE"
+        })];
+    if let Some(policy) = &settings.lifecycle_policy {
+        commands.push(
+            ecr("put-lifecycle-policy")
+                .arg("--repository-name")
+                .arg(registry.get_repository())
+                .arg("--lifecycle-policy-text")
+                .arg(policy),
+        );
+    }
+
+    script.if_fails(
+        ecr("describe-repositories")
+            .arg("--repository-names")
+            .arg(registry.get_repository())
+            .args([
+                "--query",
+                "repositories[0].repositoryUri",
+                "--output",
+                "text",
+            ]),
+        commands,
+    );
+}
+
+#[test]
+fn tar_files_streams_paths_relative_to_the_context() {
+    use flate2::read::GzDecoder;
+
+    let target_dir = Path::new("example_proj");
+    let files = vec![
+        target_dir.join("Dockerfile"),
+        target_dir.join("src/code.sh"),
+    ];
+
+    let mut archive = Vec::new();
+    tar_files(&files, target_dir, &mut archive).unwrap();
+
+    let entries: Vec<PathBuf> = tar::Archive::new(GzDecoder::new(&archive[..]))
+        .entries()
+        .unwrap()
+        .map(|entry| entry.unwrap().path().unwrap().into_owned())
+        .collect();
+
+    assert_eq!(
+        entries,
+        vec![PathBuf::from("Dockerfile"), PathBuf::from("src/code.sh")]
+    );
+}
+
+#[test]
+fn build_scripts_only_touch_their_own_build_dir() {
+    let registries =
+        vec![RegistryUri::parse("123456789012.dkr.ecr.eu-west-1.amazonaws.com/app").unwrap()];
+    let script = create_script(
+        "builds/123",
+        "builds/123/context",
+        &registries,
+        &BuildOptions::default(),
+        &["latest".to_owned()],
+        None,
+    );
+
+    assert!(script.contains("cd ~/builds/123/context"));
+    assert!(script.contains("trap 'rm -rf ~/builds/123; touch ~/.cbuilder-last-activity' EXIT"));
+    assert!(script.contains("export AWS_CONFIG_FILE=~/builds/123/aws-config"));
+    assert!(script.contains("--region eu-west-1"));
+}
+
+#[test]
+fn synced_ships_create_their_build_dir() {
+    use std::process::{Command, Stdio};
+
+    // Only the context directory exists on the instance after a sync
+    let home = std::env::temp_dir().join(format!("cbuilder-ship-test-{}", std::process::id()));
+    std::fs::create_dir_all(home.join("contexts/app")).unwrap();
+
+    let mut upload = Command::new("bash")
+        .arg("-c")
+        .arg(script_upload_command("builds/123"))
+        .current_dir(&home)
+        .stdin(Stdio::piped())
+        .spawn()
+        .unwrap();
+    upload
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"#!/bin/bash")
+        .unwrap();
+    let status = upload.wait().unwrap();
+
+    let script = std::fs::read_to_string(home.join("builds/123/script.sh"));
+    std::fs::remove_dir_all(&home).unwrap();
+
+    assert!(status.success());
+    assert_eq!(script.unwrap(), "#!/bin/bash");
+}
+
+#[test]
+fn build_scripts_push_every_tag_to_every_registry() {
+    let registries = vec![
+        RegistryUri::parse("111111111111.dkr.ecr.eu-west-1.amazonaws.com/app").unwrap(),
+        RegistryUri::parse("111111111111.dkr.ecr.eu-west-1.amazonaws.com/mirror").unwrap(),
+        RegistryUri::parse("222222222222.dkr.ecr.us-east-1.amazonaws.com/app").unwrap(),
+    ];
+    let script = create_script(
+        "builds/123",
+        "builds/123/context",
+        &registries,
+        &BuildOptions::default(),
+        &["latest".to_owned(), "v1.2".to_owned()],
+        None,
+    );
+
+    // One role & login per account and registry host
+    assert_eq!(script.matches("ContainerBuilderPushRole").count(), 2);
+    assert!(script.contains("arn:aws:iam::222222222222:role/ContainerBuilderPushRole"));
+    assert_eq!(script.matches("docker login").count(), 2);
+    assert!(script.contains("--profile account_222222222222 --region us-east-1 | docker login --username AWS --password-stdin 222222222222.dkr.ecr.us-east-1.amazonaws.com\n"));
+
+    // Built once and pushed six times
+    assert_eq!(script.matches("docker build").count(), 1);
+    assert!(script.contains("-t 111111111111.dkr.ecr.eu-west-1.amazonaws.com/mirror:v1.2"));
+    assert_eq!(script.matches("docker push").count(), 6);
+    assert!(script.contains("docker push 222222222222.dkr.ecr.us-east-1.amazonaws.com/app:latest"));
+}
+
+#[test]
+fn build_options_are_passed_to_docker_build_quoted() {
+    let registries =
+        vec![RegistryUri::parse("123456789012.dkr.ecr.eu-west-1.amazonaws.com/app").unwrap()];
+    let build_options = BuildOptions {
+        build_args: vec!["GREETING=hello world".to_owned(), "X=$(reboot)".to_owned()],
+        target: Some("release".to_owned()),
+        file: Some("docker/Dockerfile.prod".to_owned()),
+        platform: Some("linux/arm64".to_owned()),
+        labels: vec!["owner=it's me".to_owned()],
+        no_cache: true,
+        extra_args: vec!["--pull".to_owned()],
+    };
+    let script = create_script(
+        "builds/123",
+        "builds/123/context",
+        &registries,
+        &build_options,
+        &["latest".to_owned()],
+        None,
+    );
+
+    assert!(script.contains(
+        "docker build -t 123456789012.dkr.ecr.eu-west-1.amazonaws.com/app:latest \
+--build-arg 'GREETING=hello world' --build-arg 'X=$(reboot)' --target release \
+--file docker/Dockerfile.prod --platform linux/arm64 --label 'owner=it'\\''s me' \
+--no-cache --pull .\n"
+    ));
+}
+
+#[test]
+fn build_script_snapshot() {
+    let registries = vec![
+        RegistryUri::parse("111111111111.dkr.ecr.eu-west-1.amazonaws.com/app").unwrap(),
+        RegistryUri::parse("222222222222.dkr.ecr.us-east-1.amazonaws.com/team/app").unwrap(),
+    ];
+    let script = create_script(
+        "builds/123",
+        "builds/123/context",
+        &registries,
+        &BuildOptions {
+            build_args: vec!["VERSION=1.2".to_owned()],
+            ..BuildOptions::default()
+        },
+        &["latest".to_owned(), "v1.2".to_owned()],
+        None,
+    );
+
+    assert_eq!(
+        script,
+        "\
+#!/bin/bash -eux
+trap 'rm -rf ~/builds/123; touch ~/.cbuilder-last-activity' EXIT
+touch ~/.cbuilder-last-activity
+cd ~/builds/123/context
+export AWS_CONFIG_FILE=~/builds/123/aws-config
+aws configure set profile.account_111111111111.role_arn arn:aws:iam::111111111111:role/ContainerBuilderPushRole
+aws configure set profile.account_111111111111.credential_source Ec2InstanceMetadata
+aws ecr get-login-password --profile account_111111111111 --region eu-west-1 | docker login --username AWS --password-stdin 111111111111.dkr.ecr.eu-west-1.amazonaws.com
+aws configure set profile.account_222222222222.role_arn arn:aws:iam::222222222222:role/ContainerBuilderPushRole
+aws configure set profile.account_222222222222.credential_source Ec2InstanceMetadata
+aws ecr get-login-password --profile account_222222222222 --region us-east-1 | docker login --username AWS --password-stdin 222222222222.dkr.ecr.us-east-1.amazonaws.com
+docker build -t 111111111111.dkr.ecr.eu-west-1.amazonaws.com/app:latest -t 111111111111.dkr.ecr.eu-west-1.amazonaws.com/app:v1.2 -t 222222222222.dkr.ecr.us-east-1.amazonaws.com/team/app:latest -t 222222222222.dkr.ecr.us-east-1.amazonaws.com/team/app:v1.2 --build-arg 'VERSION=1.2' .
+docker push 111111111111.dkr.ecr.eu-west-1.amazonaws.com/app:latest
+docker push 111111111111.dkr.ecr.eu-west-1.amazonaws.com/app:v1.2
+docker push 222222222222.dkr.ecr.us-east-1.amazonaws.com/team/app:latest
+docker push 222222222222.dkr.ecr.us-east-1.amazonaws.com/team/app:v1.2"
+    );
+}
+
+#[test]
+fn tags_in_registry_uris_replace_the_shared_tags() {
+    let registries = vec![
+        RegistryUri::parse("111111111111.dkr.ecr.eu-west-1.amazonaws.com/app").unwrap(),
+        RegistryUri::parse("111111111111.dkr.ecr.eu-west-1.amazonaws.com/pinned:stable").unwrap(),
+    ];
+    let script = create_script(
+        "builds/123",
+        "builds/123/context",
+        &registries,
+        &BuildOptions::default(),
+        &["v1".to_owned(), "v2".to_owned()],
+        None,
+    );
+
+    assert!(script.contains("docker push 111111111111.dkr.ecr.eu-west-1.amazonaws.com/app:v2\n"));
+    assert!(
+        script.contains("docker push 111111111111.dkr.ecr.eu-west-1.amazonaws.com/pinned:stable")
+    );
+    assert!(!script.contains("pinned:v1"));
+    assert_eq!(script.matches("docker push").count(), 3);
+}
+
+#[test]
+fn missing_repositories_are_created_before_the_build() {
+    let registries = vec![
+        RegistryUri::parse("123456789012.dkr.ecr.eu-west-1.amazonaws.com/team/app").unwrap(),
+        RegistryUri::parse("123456789012.dkr.ecr.eu-west-1.amazonaws.com/team/app:pinned").unwrap(),
+        RegistryUri::parse("ghcr.io/owner/app").unwrap(),
+    ];
+    let settings = RepositorySettings {
+        scan_on_push: true,
+        immutable_tags: false,
+        lifecycle_policy: Some(r#"{"rules": []}"#.to_owned()),
+    };
+    let script = create_script(
+        "builds/123",
+        "builds/123/context",
+        &registries,
+        &BuildOptions::default(),
+        &["v1".to_owned()],
+        Some(&settings),
+    );
+
+    assert!(script.contains(
+        "\
+if ! aws ecr describe-repositories --profile account_123456789012 --region eu-west-1 \
+--repository-names team/app --query 'repositories[0].repositoryUri' --output text; then
+aws ecr create-repository --profile account_123456789012 --region eu-west-1 \
+--repository-name team/app --image-scanning-configuration 'scanOnPush=true' \
+--image-tag-mutability MUTABLE
+aws ecr put-lifecycle-policy --profile account_123456789012 --region eu-west-1 \
+--repository-name team/app --lifecycle-policy-text '{\"rules\": []}'
+fi
+docker build "
+    ));
+    // Each repository is checked once & only ECR repositories are created
+    assert_eq!(script.matches("describe-repositories").count(), 1);
+    assert!(!script.contains("owner/app --"));
+}
+
+#[test]
+fn registries_and_tags_are_validated_before_use() {
+    let plan = |registry: &str, tag: &str| {
+        ShipPlan::new(
+            Path::new("no-working-dir"),
+            "example_proj".to_owned(),
+            vec![registry.to_owned()],
+            BuildOptions::default(),
+            vec![tag.to_owned()],
+            false,
+            false,
+        )
     };
+    let registry = "123456789012.dkr.ecr.eu-west-1.amazonaws.com/app";
+
+    assert!(plan(registry, "v1.2_rc-1").is_ok());
+    assert!(matches!(
+        plan("12345.dkr.ecr.eu-west-1.amazonaws.com/app", "v1"),
+        Err(ShipError::RegistryUriInvalid(
+            RegistryUriError::InvalidAccount(_)
+        ))
+    ));
+    assert!(matches!(
+        plan(
+            "123456789012.dkr.ecr.eu-west-1.amazonaws.com/app;reboot",
+            "v1"
+        ),
+        Err(ShipError::RegistryUriInvalid(_))
+    ));
+    assert!(matches!(
+        plan("123456789012.dkr.ecr.eu-west-1.amazonaws.com/App", "v1"),
+        Err(ShipError::RegistryUriInvalid(_))
+    ));
+    assert!(matches!(
+        plan(registry, "v1 && reboot"),
+        Err(ShipError::TagInvalid(_))
+    ));
+    assert!(matches!(
+        plan(registry, ".hidden"),
+        Err(ShipError::TagInvalid(_))
+    ));
+}
+
+#[test]
+fn dockerfiles_must_be_inside_the_context() {
+    for file in &["../Dockerfile", "/etc/Dockerfile"] {
+        let result = ShipPlan::new(
+            Path::new("no-working-dir"),
+            "example_proj".to_owned(),
+            vec!["123456789012.dkr.ecr.eu-west-1.amazonaws.com/app".to_owned()],
+            BuildOptions {
+                file: Some(file.to_string()),
+                ..BuildOptions::default()
+            },
+            vec!["v1".to_owned()],
+            false,
+            false,
+        );
+        assert!(matches!(
+            result,
+            Err(ShipError::DockerfileOutsideContext(_))
+        ));
+    }
+}
+
+#[cfg(test)]
+const STUB_AWS: &str = "#!/bin/bash\nexit 0\n";
+
+// docker records how it was called & what the build context contained
+#[cfg(test)]
+const STUB_DOCKER: &str = "#!/bin/bash
+echo \"docker $*\" >> ~/docker.log
+if [ \"$1\" = build ]; then find . -type f | sort >> ~/docker.log; fi
+if [ \"$1\" = login ]; then echo \"password $(cat) config ${DOCKER_CONFIG:-none}\" >> ~/docker.log; fi
+";
+
+#[cfg(test)]
+fn create_test_plan(sync: bool) -> ShipPlan {
+    ShipPlan::new(
+        Path::new("no-working-dir"),
+        "example_proj".to_owned(),
+        vec!["123456789012.dkr.ecr.eu-west-1.amazonaws.com/app".to_owned()],
+        BuildOptions::default(),
+        vec!["v1".to_owned()],
+        false,
+        sync,
+    )
+    .unwrap()
+}
 
-    // Create the script itself
+#[tokio::test]
+async fn ship_builds_and_pushes_the_filtered_context() {
+    let home = tempfile::tempdir().unwrap();
+    let transport = crate::LocalTransport::new(home.path())
+        .with_stubs(&[("aws", STUB_AWS), ("docker", STUB_DOCKER)]);
 
-    let mut script = Vec::new();
-    script.push("#!/bin/bash -eux".to_owned());
-    script.push("mkdir archive".to_owned());
-    script.push("tar -xzvf archive.tar.gz -C archive".to_owned());
-    script.push("cd archive".to_owned());
-    script.push(format!("aws configure set profile.target_profile.role_arn arn:aws:iam::{}:role/ContainerBuilderPushRole", target_account));
-    script.push(
-        "aws configure set profile.target_profile.credential_source Ec2InstanceMetadata".to_owned(),
+    run_ship(&transport, create_test_plan(false)).await.unwrap();
+
+    let log = std::fs::read_to_string(home.path().join("docker.log")).unwrap();
+    assert!(log.contains("docker build -t 123456789012.dkr.ecr.eu-west-1.amazonaws.com/app:v1"));
+    assert!(log.contains("./Dockerfile\n./src/code.sh\n"));
+    assert!(!log.contains("dependencies"));
+    assert!(log.contains("docker push 123456789012.dkr.ecr.eu-west-1.amazonaws.com/app:v1"));
+
+    // The build directory is removed once the build finishes
+    let builds: Vec<_> = read_dir(home.path().join("builds")).unwrap().collect();
+    assert!(builds.is_empty());
+}
+
+#[tokio::test]
+async fn ship_reports_the_exit_status_of_a_failed_build() {
+    let home = tempfile::tempdir().unwrap();
+    let transport = crate::LocalTransport::new(home.path()).with_stubs(&[
+        ("aws", STUB_AWS),
+        ("docker", "#!/bin/bash\necho 'build broke' >&2\nexit 3\n"),
+    ]);
+
+    match run_ship(&transport, create_test_plan(false)).await {
+        Err(ShipError::ScriptExitCodeError {
+            exit_status,
+            output_tail,
+        }) => {
+            assert_eq!(exit_status, 3);
+            assert!(output_tail.contains(&"build broke".to_owned()));
+        }
+        other => panic!("Unexpected result: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn finished_builds_reset_the_idle_clock() {
+    let home = tempfile::tempdir().unwrap();
+    // A build longer than the idle time leaves the marker as old as when it started
+    let transport = crate::LocalTransport::new(home.path()).with_stubs(&[
+        ("aws", STUB_AWS),
+        (
+            "docker",
+            "#!/bin/bash\ntouch -d '2 hours ago' ~/.cbuilder-last-activity\n",
+        ),
+    ]);
+
+    run_ship(&transport, create_test_plan(false)).await.unwrap();
+
+    let marked_at = std::fs::metadata(home.path().join(".cbuilder-last-activity"))
+        .unwrap()
+        .modified()
+        .unwrap();
+    let idle_for = std::time::SystemTime::now()
+        .duration_since(marked_at)
+        .unwrap_or_default();
+    assert!(idle_for < std::time::Duration::from_secs(60));
+}
+
+#[tokio::test]
+async fn synced_ships_reuse_the_context_on_the_builder() {
+    let home = tempfile::tempdir().unwrap();
+    let transport = crate::LocalTransport::new(home.path())
+        .with_stubs(&[("aws", STUB_AWS), ("docker", STUB_DOCKER)]);
+
+    run_ship(&transport, create_test_plan(true)).await.unwrap();
+    let name = context_name(Path::new("example_proj")).unwrap();
+    assert!(transport
+        .exists(&format!("contexts/{}/src/code.sh", name))
+        .await
+        .unwrap());
+    let manifest = transport
+        .read_file(&format!("contexts/{}.manifest", name))
+        .await
+        .unwrap();
+    assert!(manifest.is_some());
+
+    // A removed context is sent again in full even though its manifest is unchanged
+    std::fs::remove_dir_all(home.path().join("contexts").join(&name)).unwrap();
+    run_ship(&transport, create_test_plan(true)).await.unwrap();
+    assert!(transport
+        .exists(&format!("contexts/{}/Dockerfile", name))
+        .await
+        .unwrap());
+}
+
+#[tokio::test]
+async fn other_registries_log_in_with_credentials_from_stdin() {
+    let home = tempfile::tempdir().unwrap();
+    let working_dir = tempfile::tempdir().unwrap();
+    std::fs::write(
+        working_dir.path().join("registry-credentials.yml"),
+        "harbor.internal:5000:\n  username: robot\n  password: \"p@ss $(word)\"\n",
+    )
+    .unwrap();
+    let transport = crate::LocalTransport::new(home.path())
+        .with_stubs(&[("aws", STUB_AWS), ("docker", STUB_DOCKER)]);
+
+    let plan = ShipPlan::new(
+        working_dir.path(),
+        "example_proj".to_owned(),
+        vec![
+            "harbor.internal:5000/team/app".to_owned(),
+            "123456789012.dkr.ecr.eu-west-1.amazonaws.com/app".to_owned(),
+        ],
+        BuildOptions::default(),
+        vec!["v1".to_owned()],
+        false,
+        false,
+    )
+    .unwrap();
+    run_ship(&transport, plan).await.unwrap();
+
+    let log = std::fs::read_to_string(home.path().join("docker.log")).unwrap();
+    assert!(log.contains("docker login --username robot --password-stdin harbor.internal:5000\n"));
+    assert!(log.contains("docker push harbor.internal:5000/team/app:v1"));
+    assert!(log.contains("docker push 123456789012.dkr.ecr.eu-west-1.amazonaws.com/app:v1"));
+
+    // The password only reaches docker login, & its config is kept in memory then removed
+    let login = log
+        .lines()
+        .find(|line| line.starts_with("password p@ss"))
+        .unwrap();
+    assert!(login.starts_with("password p@ss $(word) config /dev/shm/"));
+    let docker_config = login.split(" config ").nth(1).unwrap();
+    assert!(!Path::new(docker_config).exists());
+}
+
+#[test]
+fn other_registries_need_credentials() {
+    let working_dir = tempfile::tempdir().unwrap();
+    let result = ShipPlan::new(
+        working_dir.path(),
+        "example_proj".to_owned(),
+        vec!["registry.invalid/team/app".to_owned()],
+        BuildOptions::default(),
+        vec!["v1".to_owned()],
+        false,
+        false,
     );
-    script.push(format!("aws ecr get-login-password --profile target_profile --region us-east-1 | docker login --username AWS --password-stdin {}", registry_uri));
-    script.push(format!(
-        "docker build -t {}:{} {} .",
-        registry_uri, tag, build_args
+
+    assert!(matches!(
+        result,
+        Err(ShipError::RegistryCredentialsFailed(
+            RegistryCredentialsError::NotFound(_)
+        ))
     ));
-    script.push(format!("docker push {}:{}", registry_uri, tag));
-    script.push("cd ..".to_owned());
-    script.push("rm -rf archive".to_owned());
-
-    let script_data = script.join("\n");
-
-    // Write into file
-    let mut script_file: File =
-        File::create("script.sh").map_err(|_| ShipError::ScriptCreateFailed)?;
-    script_file
-        .write(script_data.as_bytes())
-        .map_err(|_| ShipError::ScriptCreateFailed)?;
-
-    // Set file execute permissions
-    if cfg!(unix) {
-        // Set readonly permissions on the file if on unix
-        let metadata = script_file
-            .metadata()
-            .map_err(|_| ShipError::ScriptCreateFailed)?;
-
-        let mut permissions = metadata.permissions();
-        permissions.set_mode(0o777);
-        script_file
-            .set_permissions(permissions)
-            .map_err(|_| ShipError::ScriptCreateFailed)?;
-    }
-
-    Ok(())
 }
@@ -1,14 +1,18 @@
 use crate::Config;
-use crate::{CfnClient, EC2Client};
-use std::{thread::sleep, time::Duration};
+use crate::{create_transport, AwsClientFactory, CfnClient, EC2Client, HostKeys};
+use std::path::Path;
+use std::time::Duration;
 
 #[derive(Debug)]
 pub enum StartError {
     CouldNotFindConfig,
     InstanceNotFound,
     FailedToStart,
+    InstanceDidNotStart(String),
     DescribeInstanceFailed,
     FailedSaveConfig,
+    InstanceNotReady(String),
+    FailedPinHostKeys(String),
 }
 
 pub async fn run_start() -> Result<(), StartError> {
@@ -21,9 +25,15 @@ pub async fn run_start() -> Result<(), StartError> {
     let mut config =
         Config::read_from_file(&props_file_path).ok_or(StartError::CouldNotFindConfig)?;
 
-    let ec2_client = EC2Client::new(config.get_base_profile());
+    ensure_running(&mut config, &working_dir).await
+}
 
-    let cfn_client = CfnClient::new(config.get_base_profile());
+/// Start the instance unless it is already running and wait until it can run builds.
+/// The instance gets a new IP on every start so the IP in the config is refreshed.
+pub async fn ensure_running(config: &mut Config, working_dir: &Path) -> Result<(), StartError> {
+    let aws = AwsClientFactory::new(config.get_base_profile(), config.get_region());
+    let ec2_client = EC2Client::new(&aws);
+    let cfn_client = CfnClient::new(&aws);
 
     // Get the instance ID
     let instance_id = cfn_client
@@ -31,14 +41,38 @@ pub async fn run_start() -> Result<(), StartError> {
         .await
         .ok_or(StartError::InstanceNotFound)?;
 
-    let started_instance = ec2_client.start_instance(instance_id.clone()).await;
+    let state = ec2_client
+        .get_instance_state(instance_id.clone())
+        .await
+        .ok_or(StartError::DescribeInstanceFailed)?;
+
+    let needs_start = state != "running" && state != "pending";
+    if needs_start {
+        println!("Builder instance is {}, starting it", state);
+
+        // An instance can't be started until it has finished stopping
+        if state == "stopping" {
+            ec2_client
+                .wait_for_instance_state(
+                    instance_id.clone(),
+                    "stopped",
+                    Duration::from_secs(5 * 60),
+                )
+                .await
+                .map_err(|err| StartError::InstanceDidNotStart(format!("{:?}", err)))?;
+        }
 
-    if !started_instance {
-        return Err(StartError::FailedToStart);
+        let started_instance = ec2_client.start_instance(instance_id.clone()).await;
+
+        if !started_instance {
+            return Err(StartError::FailedToStart);
+        }
     }
 
-    // TODO: Fix this!
-    sleep(Duration::from_secs(60));
+    ec2_client
+        .wait_for_instance_state(instance_id.clone(), "running", Duration::from_secs(5 * 60))
+        .await
+        .map_err(|err| StartError::InstanceDidNotStart(format!("{:?}", err)))?;
 
     // Find the new IP of the instance
     let instance_ip = ec2_client
@@ -46,11 +80,28 @@ pub async fn run_start() -> Result<(), StartError> {
         .await
         .ok_or(StartError::DescribeInstanceFailed)?;
 
-    config.set_instance_ip(instance_ip);
+    let ip_changed = instance_ip != config.get_instance_ip();
+    if ip_changed {
+        // The instance keeps its host keys when it restarts so they must still match
+        HostKeys::new(working_dir.join("known_hosts"))
+            .repin(&config.get_instance_ip(), &instance_ip)
+            .map_err(|err| StartError::FailedPinHostKeys(format!("{:?}", err)))?;
+        config.set_instance_ip(instance_ip.clone());
 
-    config
-        .write_to_file(&props_file_path)
-        .map_err(|_| StartError::FailedSaveConfig)?;
+        config
+            .write_to_file(&working_dir.join("properties.yml"))
+            .map_err(|_| StartError::FailedSaveConfig)?;
+    }
+
+    // Only report success once builds can actually run
+    if state != "running" || ip_changed {
+        create_transport(config, working_dir)
+            .await
+            .map_err(|err| StartError::InstanceNotReady(format!("{:?}", err)))?
+            .wait_until_ready(Duration::from_secs(5 * 60))
+            .await
+            .map_err(|err| StartError::InstanceNotReady(format!("{:?}", err)))?;
+    }
 
     Ok(())
 }
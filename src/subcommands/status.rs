@@ -0,0 +1,287 @@
+use crate::{AwsClientFactory, CfnClient, Config, EC2Client};
+use futures::future::join_all;
+use serde::Serialize;
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum StatusError {
+    CouldNotFindConfig,
+}
+
+#[derive(Serialize, Debug)]
+pub struct StatusReport {
+    region: String,
+    stack_status: StackStatus,
+    instance: Option<InstanceStatus>,
+    config_ip: String,
+    config_ip_stale: Option<bool>,
+    sub_accounts: Vec<SubAccountStatus>,
+    key_file: KeyFileStatus,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "state", content = "detail", rename_all = "snake_case")]
+enum StackStatus {
+    Exists(String),
+    Missing,
+    Unknown(String),
+}
+
+#[derive(Serialize, Debug)]
+struct InstanceStatus {
+    id: String,
+    state: String,
+    instance_type: String,
+    public_ip: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct SubAccountStatus {
+    account_no: String,
+    profile: String,
+    role_stack_status: StackStatus,
+}
+
+#[derive(Serialize, Debug)]
+struct KeyFileStatus {
+    path: String,
+    exists: bool,
+    mode: Option<String>,
+    permissions_ok: bool,
+}
+
+pub async fn get_status() -> Result<StatusReport, StatusError> {
+    let home_dir = dirs::home_dir().expect("Could not find home directory");
+    let working_dir = home_dir.join(".cbuilder");
+    let config = Config::read_from_file(&working_dir.join("properties.yml"))
+        .ok_or(StatusError::CouldNotFindConfig)?;
+
+    let aws = AwsClientFactory::new(config.get_base_profile(), config.get_region());
+    let cfn_client = CfnClient::new(&aws);
+    let ec2_client = EC2Client::new(&aws);
+
+    let stack_status = get_stack_status(&cfn_client, "container-builder").await;
+
+    // The instance can only be looked up through the stack
+    let instance = match cfn_client.get_instance_id().await {
+        Some(id) => ec2_client
+            .get_instance_details(id.clone())
+            .await
+            .map(|details| InstanceStatus {
+                id,
+                state: details.state,
+                instance_type: details.instance_type,
+                public_ip: details.public_ip,
+            }),
+        None => None,
+    };
+
+    let config_ip_stale = instance
+        .as_ref()
+        .map(|instance| is_ip_stale(&config.get_instance_ip(), instance));
+
+    let sub_accounts = join_all(config.get_account_numbers().into_iter().map(|account_no| {
+        let profile = config
+            .get_account_profile(account_no.clone())
+            .unwrap_or_default();
+        let region = config.get_region();
+        async move {
+            let cfn_client_sub = CfnClient::new(&AwsClientFactory::with_explicit_profile(
+                profile.clone(),
+                region,
+            ));
+            SubAccountStatus {
+                account_no,
+                profile,
+                role_stack_status: get_stack_status(&cfn_client_sub, "container-builder-role")
+                    .await,
+            }
+        }
+    }))
+    .await;
+
+    Ok(StatusReport {
+        region: config.get_region().name().to_owned(),
+        stack_status,
+        instance,
+        config_ip: config.get_instance_ip(),
+        config_ip_stale,
+        sub_accounts,
+        key_file: get_key_file_status(&working_dir.join("ContainerBuilderKey.pem")),
+    })
+}
+
+async fn get_stack_status(cfn_client: &CfnClient, stack_name: &str) -> StackStatus {
+    match cfn_client.get_stack_status(stack_name.to_owned()).await {
+        Ok(Some(status)) => StackStatus::Exists(status),
+        Ok(None) => StackStatus::Missing,
+        Err(err) => StackStatus::Unknown(err),
+    }
+}
+
+fn is_ip_stale(config_ip: &str, instance: &InstanceStatus) -> bool {
+    // A stopped instance has no IP so there is nothing to compare against
+    match &instance.public_ip {
+        Some(ip) => ip != config_ip,
+        None => false,
+    }
+}
+
+#[cfg(unix)]
+fn get_key_file_status(path: &Path) -> KeyFileStatus {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = std::fs::metadata(path)
+        .ok()
+        .map(|metadata| metadata.permissions().mode() & 0o777);
+
+    // ssh refuses keys which can be read by other users
+    KeyFileStatus {
+        path: path.to_string_lossy().into_owned(),
+        exists: mode.is_some(),
+        mode: mode.map(|mode| format!("{:o}", mode)),
+        permissions_ok: matches!(mode, Some(mode) if mode & 0o077 == 0),
+    }
+}
+
+#[cfg(not(unix))]
+fn get_key_file_status(path: &Path) -> KeyFileStatus {
+    let exists = path.exists();
+
+    KeyFileStatus {
+        path: path.to_string_lossy().into_owned(),
+        exists,
+        mode: None,
+        permissions_ok: exists,
+    }
+}
+
+impl StatusReport {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Status report should serialize")
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut lines = Vec::new();
+        lines.push(format!("Region:          {}", self.region));
+        lines.push(format!(
+            "Stack:           container-builder ({})",
+            describe_stack_status(&self.stack_status)
+        ));
+
+        match &self.instance {
+            Some(instance) => {
+                lines.push(format!(
+                    "Instance:        {} ({}, {})",
+                    instance.id, instance.state, instance.instance_type
+                ));
+                lines.push(format!(
+                    "Instance IP:     {}",
+                    instance.public_ip.as_deref().unwrap_or("none")
+                ));
+            }
+            None => lines.push("Instance:        not found".to_owned()),
+        }
+
+        let stale_note = match self.config_ip_stale {
+            Some(true) => " (stale, run `builder start` to refresh)",
+            _ => "",
+        };
+        lines.push(format!("Configured IP:   {}{}", self.config_ip, stale_note));
+
+        if self.sub_accounts.is_empty() {
+            lines.push("Sub-accounts:    none".to_owned());
+        } else {
+            lines.push("Sub-accounts:".to_owned());
+            for account in &self.sub_accounts {
+                lines.push(format!(
+                    "    {} (profile {}): container-builder-role {}",
+                    account.account_no,
+                    account.profile,
+                    describe_stack_status(&account.role_stack_status)
+                ));
+            }
+        }
+
+        let key_status = match (&self.key_file.exists, &self.key_file.mode) {
+            (false, _) => "missing".to_owned(),
+            (true, Some(mode)) if self.key_file.permissions_ok => format!("ok, mode {}", mode),
+            (true, Some(mode)) => format!("mode {} is too open, run chmod 600", mode),
+            (true, None) => "present".to_owned(),
+        };
+        lines.push(format!(
+            "SSH key:         {} ({})",
+            self.key_file.path, key_status
+        ));
+
+        lines.join("\n")
+    }
+}
+
+fn describe_stack_status(status: &StackStatus) -> String {
+    match status {
+        StackStatus::Exists(status) => status.clone(),
+        StackStatus::Missing => "does not exist".to_owned(),
+        StackStatus::Unknown(err) => format!("could not be described: {}", err),
+    }
+}
+
+#[cfg(test)]
+fn example_report() -> StatusReport {
+    StatusReport {
+        region: "eu-west-1".to_owned(),
+        stack_status: StackStatus::Exists("CREATE_COMPLETE".to_owned()),
+        instance: Some(InstanceStatus {
+            id: "i-0123".to_owned(),
+            state: "running".to_owned(),
+            instance_type: "t2.micro".to_owned(),
+            public_ip: Some("1.2.3.4".to_owned()),
+        }),
+        config_ip: "5.6.7.8".to_owned(),
+        config_ip_stale: Some(true),
+        sub_accounts: vec![SubAccountStatus {
+            account_no: "123456789012".to_owned(),
+            profile: "dev".to_owned(),
+            role_stack_status: StackStatus::Missing,
+        }],
+        key_file: KeyFileStatus {
+            path: "/home/me/.cbuilder/ContainerBuilderKey.pem".to_owned(),
+            exists: true,
+            mode: Some("644".to_owned()),
+            permissions_ok: false,
+        },
+    }
+}
+
+#[test]
+fn ip_is_only_stale_when_the_instance_has_a_different_ip() {
+    let mut instance = example_report().instance.unwrap();
+    assert!(is_ip_stale("5.6.7.8", &instance));
+    assert!(!is_ip_stale("1.2.3.4", &instance));
+
+    instance.public_ip = None;
+    assert!(!is_ip_stale("5.6.7.8", &instance));
+}
+
+#[test]
+fn text_status_reports_problems() {
+    let text = example_report().to_text();
+
+    assert!(text.contains("Instance:        i-0123 (running, t2.micro)"));
+    assert!(text.contains("5.6.7.8 (stale"));
+    assert!(text.contains("123456789012 (profile dev): container-builder-role does not exist"));
+    assert!(text.contains("mode 644 is too open"));
+}
+
+#[test]
+fn json_status_includes_stack_states() {
+    let json: serde_json::Value = serde_json::from_str(&example_report().to_json()).unwrap();
+
+    assert_eq!(json["stack_status"]["state"], "exists");
+    assert_eq!(json["stack_status"]["detail"], "CREATE_COMPLETE");
+    assert_eq!(
+        json["sub_accounts"][0]["role_stack_status"]["state"],
+        "missing"
+    );
+    assert_eq!(json["config_ip_stale"], true);
+}
@@ -1,4 +1,5 @@
-use crate::{CfnClient, Config, EC2Client};
+use crate::{AwsClientFactory, CfnClient, Config, EC2Client};
+use std::time::Duration;
 
 pub async fn run_stop() -> Result<(), String> {
     // Get the home directory
@@ -9,9 +10,9 @@ pub async fn run_stop() -> Result<(), String> {
     let config = Config::read_from_file(&working_dir.join("properties.yml"))
         .expect("Could not load config file");
 
-    let ec2_client = EC2Client::new(config.get_base_profile());
-
-    let cfn_client = CfnClient::new(config.get_base_profile());
+    let aws = AwsClientFactory::new(config.get_base_profile(), config.get_region());
+    let ec2_client = EC2Client::new(&aws);
+    let cfn_client = CfnClient::new(&aws);
 
     // Find the instance id
     let instance_id = cfn_client
@@ -20,11 +21,14 @@ pub async fn run_stop() -> Result<(), String> {
         .ok_or("Failed to get instance Id".to_owned())?;
 
     // Stop the instance
-    let stopped_instance = ec2_client.stop_instance(instance_id).await;
+    let stopped_instance = ec2_client.stop_instance(instance_id.clone()).await;
 
     if !stopped_instance {
         return Err("Failed to stop instance".to_owned());
-    } else {
-        return Ok(());
     }
+
+    ec2_client
+        .wait_for_instance_state(instance_id, "stopped", Duration::from_secs(5 * 60))
+        .await
+        .map_err(|err| format!("Instance did not stop: {:?}", err))
 }
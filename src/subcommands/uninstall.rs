@@ -1,4 +1,4 @@
-use crate::{CfnClient, Config, EC2Client};
+use crate::{AwsClientFactory, CfnClient, Config, EC2Client};
 use futures::future::join_all;
 
 #[derive(Debug)]
@@ -17,7 +17,8 @@ pub async fn uninstall() -> Result<(), UninstallError> {
     let config =
         Config::read_from_file(&props_file_path).ok_or(UninstallError::CouldNotFindConfig)?;
 
-    let cfn_client = CfnClient::new(config.get_base_profile());
+    let aws = AwsClientFactory::new(config.get_base_profile(), config.get_region());
+    let cfn_client = CfnClient::new(&aws);
     let deleted_stack = cfn_client
         .delete_stack("container-builder".to_owned())
         .await;
@@ -29,11 +30,12 @@ pub async fn uninstall() -> Result<(), UninstallError> {
     // Delete the stacks from all the other accounts
     let results: Vec<UninstallError> = join_all(config.get_account_numbers().into_iter().map(
         |account_id| async {
-            let cfn_client_sub = CfnClient::new(
+            let cfn_client_sub = CfnClient::new(&AwsClientFactory::with_explicit_profile(
                 config
                     .get_account_profile(account_id)
                     .expect("Failed to get profile for account id"),
-            );
+                config.get_region(),
+            ));
 
             cfn_client_sub
                 .delete_stack("container-builder-role".to_owned())
@@ -56,7 +58,7 @@ pub async fn uninstall() -> Result<(), UninstallError> {
     }
 
     // delete the ssh key
-    let ec2_client = EC2Client::new(config.get_base_profile());
+    let ec2_client = EC2Client::new(&aws);
 
     let deleted_key = ec2_client
         .delete_key_pair("ContainerBuilderKey".to_owned())